@@ -0,0 +1,6 @@
+// sqlx::migrate! embeds migrations/ at compile time and the query macros read sqlx-data.json
+// when there's no DATABASE_URL. rebuild when either changes
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=sqlx-data.json");
+}
@@ -0,0 +1,37 @@
+-- the tables the jobs read and write. the web app created them first, so every
+-- statement is a no-op against an existing database
+
+CREATE TABLE IF NOT EXISTS challenge (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR NOT NULL,
+    time_limit INTEGER,
+    opponent_time_limit INTEGER,
+    increment INTEGER,
+    color VARCHAR,
+    sats BIGINT,
+    opp_username VARCHAR NOT NULL,
+    status VARCHAR,
+    lichess_challenge_id VARCHAR,
+    created_on TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'),
+    expire_after INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS lightningchess_transaction (
+    transaction_id SERIAL PRIMARY KEY,
+    username VARCHAR NOT NULL,
+    ttype VARCHAR NOT NULL,
+    detail VARCHAR NOT NULL,
+    amount BIGINT NOT NULL,
+    state VARCHAR NOT NULL,
+    preimage VARCHAR,
+    payment_addr VARCHAR,
+    payment_request VARCHAR,
+    payment_hash VARCHAR,
+    lichess_challenge_id VARCHAR,
+    created_on TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+);
+
+CREATE TABLE IF NOT EXISTS lightningchess_balance (
+    username VARCHAR PRIMARY KEY,
+    balance BIGINT NOT NULL DEFAULT 0
+);
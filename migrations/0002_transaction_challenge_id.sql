@@ -0,0 +1,3 @@
+-- links ledger rows to the challenge they belong to. the web app's table predates the column,
+-- so it's added here. the CREATE TABLE in 0001 is a no-op against that table
+ALTER TABLE lightningchess_transaction ADD COLUMN IF NOT EXISTS challenge_id INTEGER;
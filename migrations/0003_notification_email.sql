@@ -0,0 +1,6 @@
+-- where the email sink sends a user's notifications. the web app fills it in when a user adds an
+-- address. users without a row are skipped by the email sink
+CREATE TABLE IF NOT EXISTS lightningchess_notification_email (
+    username VARCHAR PRIMARY KEY,
+    email VARCHAR NOT NULL
+);
@@ -0,0 +1,23 @@
+-- the outboxes the jobs write in the same transaction as each settlement and deposit. unlike the
+-- tables in 0001 they're owned by the jobs, so they have to exist before any job runs. startup
+-- checks every migration has been applied for that reason
+
+CREATE TABLE IF NOT EXISTS lightningchess_event_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    event_type VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created_on TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    dispatched_on TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS lightningchess_notification_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    username VARCHAR NOT NULL,
+    channel VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    delivered_on TIMESTAMP,
+    created_on TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'UTC')
+);
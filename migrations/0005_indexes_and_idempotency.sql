@@ -0,0 +1,17 @@
+-- indexes for the jobs' lookups and unique constraints that make a second settlement of the
+-- same challenge or invoice fail instead of paying out twice. creating the unique indexes
+-- fails if existing rows already break them, so duplicates have to be cleaned up first
+
+-- the settlement and expiry passes select by status, oldest first
+CREATE INDEX IF NOT EXISTS challenge_status_created_on_idx ON challenge (status, created_on);
+
+-- one invoice per payment_addr. the invoice stream and reconciliation look deposits up by it
+CREATE UNIQUE INDEX IF NOT EXISTS lightningchess_transaction_payment_addr_key
+    ON lightningchess_transaction (payment_addr) WHERE payment_addr IS NOT NULL;
+
+-- a challenge writes at most one row of each type per user: the stake, then the fee, winnings,
+-- draw, expired or refund row that settles it. also serves the ledger lookups by challenge.
+-- rows written before 0002 added challenge_id keep it NULL and aren't covered. there's no reliable
+-- way to tell which challenge an old row belongs to, so they're left alone rather than guessed at
+CREATE UNIQUE INDEX IF NOT EXISTS lightningchess_transaction_challenge_key
+    ON lightningchess_transaction (challenge_id, username, ttype) WHERE challenge_id IS NOT NULL;
@@ -0,0 +1,3 @@
+-- the settlement, expiry and reconciliation passes page through their backlog by id within a status
+CREATE INDEX IF NOT EXISTS challenge_status_id_idx ON challenge (status, id);
+CREATE INDEX IF NOT EXISTS lightningchess_transaction_state_id_idx ON lightningchess_transaction (state, transaction_id);
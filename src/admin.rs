@@ -0,0 +1,158 @@
+use sqlx::{Pool, Postgres};
+use tracing::{info, instrument};
+use crate::config::Config;
+use crate::db_checks::apply_plan;
+use crate::repository::{get_challenge_ledger, lock_challenge_row};
+use crate::error::{LightningChessError, LightningChessResult};
+use crate::models::{AdminError, Challenge};
+use crate::notifications::Notifier;
+use crate::settlement::{format_fee_percent, plan_refund, plan_settlement, Outcome, Refund};
+
+// operator commands for challenges the jobs can't finish on their own. they go through the same
+// settlement planner as the jobs and note who asked for it in the ledger detail
+
+const ACCEPTED: &str = "ACCEPTED";
+const WAITING_FOR_ACCEPTANCE: &str = "WAITING FOR ACCEPTANCE";
+
+fn notifier(config: &Config) -> LightningChessResult<Notifier> {
+    Notifier::from_config(config).map_err(|e| LightningChessError::Config(e.to_string()))
+}
+
+// the status of a challenge that can still be acted on
+fn active_status(challenge: &Challenge) -> Result<&str, AdminError> {
+    let status = challenge.status.as_deref().unwrap_or("");
+    if status == ACCEPTED || status == WAITING_FOR_ACCEPTANCE {
+        Ok(status)
+    } else {
+        Err(AdminError::Terminal { challenge_id: challenge.id, status: status.to_string() })
+    }
+}
+
+// None is a draw
+fn resolve_winner(challenge: &Challenge, winner: &str) -> Result<Option<String>, AdminError> {
+    if winner == challenge.username || winner == challenge.opp_username {
+        Ok(Some(winner.to_string()))
+    } else if winner == "draw" {
+        Ok(None)
+    } else {
+        Err(AdminError::UnknownWinner { challenge_id: challenge.id, winner: winner.to_string() })
+    }
+}
+
+// settles an accepted challenge as if lichess reported this result. the fee is charged as usual
+#[instrument(skip_all, fields(challenge_id, winner))]
+pub async fn settle(config: &Config, pool: &Pool<Postgres>, challenge_id: i32, winner: &str, note: &str) -> LightningChessResult<()> {
+    let notifier = notifier(config)?;
+    let mut tx = pool.begin().await?;
+    let challenge = lock_challenge_row(&mut tx, challenge_id).await?.ok_or(AdminError::NotFound { challenge_id })?;
+    let status = active_status(&challenge)?;
+    if status != ACCEPTED {
+        return Err(AdminError::NotAccepted { challenge_id, status: status.to_string() }.into());
+    }
+    let game = format!("lichess game https://lichess.org/{}", challenge.lichess_challenge_id.as_deref().unwrap_or(""));
+    let (outcome, detail) = match resolve_winner(&challenge, winner)? {
+        Some(winner_username) => (Outcome::Win(winner_username), format!("{}. settled by operator: {}", game, note)),
+        None => (Outcome::Draw, format!("{}. initial sats minus {}% fee. settled by operator: {}", game, format_fee_percent(config.settlement.fee_basis_points), note))
+    };
+    let plan = plan_settlement(&challenge, &outcome, config.settlement.fee_basis_points, &config.settlement.admin_account, &detail)?;
+    apply_plan(&mut tx, &notifier, &challenge, &plan).await?;
+    tx.commit().await?;
+    info!(?outcome, ledger = ?plan.ledger, "challenge settled by operator");
+    Ok(())
+}
+
+// returns what the ledger shows each player staked, without a fee. an accepted challenge is
+// completed and one that was never accepted is voided. players are told support refunded it
+#[instrument(skip_all, fields(challenge_id))]
+pub async fn refund(config: &Config, pool: &Pool<Postgres>, challenge_id: i32, note: &str) -> LightningChessResult<()> {
+    let notifier = notifier(config)?;
+    let mut tx = pool.begin().await?;
+    let challenge = lock_challenge_row(&mut tx, challenge_id).await?.ok_or(AdminError::NotFound { challenge_id })?;
+    active_status(&challenge)?;
+    let ledger = get_challenge_ledger(&mut tx, &challenge).await?;
+    let detail = format!("sats returned for challenge {}. refunded by operator: {}", challenge_id, note);
+    let plan = plan_refund(&challenge, &Refund::Operator { note: note.to_string() }, &ledger, &detail)?;
+    apply_plan(&mut tx, &notifier, &challenge, &plan).await?;
+    tx.commit().await?;
+    info!(ledger = ?plan.ledger, "challenge refunded by operator");
+    Ok(())
+}
+
+// cancels the challenge and returns exactly what the ledger shows each player paid in for it
+#[instrument(skip_all, fields(challenge_id))]
+pub async fn void(config: &Config, pool: &Pool<Postgres>, challenge_id: i32, note: &str) -> LightningChessResult<()> {
+    let notifier = notifier(config)?;
+    let mut tx = pool.begin().await?;
+    let challenge = lock_challenge_row(&mut tx, challenge_id).await?.ok_or(AdminError::NotFound { challenge_id })?;
+    active_status(&challenge)?;
+    let ledger = get_challenge_ledger(&mut tx, &challenge).await?;
+    let detail = format!("sats returned for cancelled challenge {}. voided by operator: {}", challenge_id, note);
+    let plan = plan_refund(&challenge, &Refund::Void { note: note.to_string() }, &ledger, &detail)?;
+    apply_plan(&mut tx, &notifier, &challenge, &plan).await?;
+    tx.commit().await?;
+    info!(num_refunds = plan.ledger.len(), "challenge voided by operator");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{test_challenge, test_db};
+
+    fn get_challenge(status: &str) -> Challenge {
+        Challenge { status: Some(status.to_string()), ..test_challenge() }
+    }
+
+    #[test]
+    fn refuses_terminal_challenges() {
+        assert_eq!(active_status(&get_challenge("ACCEPTED")), Ok("ACCEPTED"));
+        assert_eq!(active_status(&get_challenge("WAITING FOR ACCEPTANCE")), Ok("WAITING FOR ACCEPTANCE"));
+        for status in ["COMPLETED", "EXPIRED", "VOID"] {
+            assert_eq!(active_status(&get_challenge(status)), Err(AdminError::Terminal { challenge_id: 1, status: status.to_string() }));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn refunding_a_waiting_challenge_doesnt_expire_it() {
+        let db = test_db().await;
+        db.set_balance("alice", 0).await;
+        let challenge_id = db.challenge("WAITING FOR ACCEPTANCE", "white", "abc", 0).await;
+
+        refund(&db.config("", ""), &db.pool, challenge_id, "asked by alice").await.unwrap();
+        assert_eq!(db.status(challenge_id).await, "VOID");
+        assert_eq!(db.ledger(challenge_id).await[1], ("alice".to_string(), "refund".to_string(), 100));
+        assert_eq!(db.balance("alice").await, 100);
+        let (event_type,) = sqlx::query_as::<_, (String,)>("SELECT event_type FROM lightningchess_event_outbox")
+            .fetch_one(&db.pool).await.unwrap();
+        assert_eq!(event_type, "ChallengeRefunded");
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn refunding_an_accepted_challenge_returns_the_ledger_stakes() {
+        let db = test_db().await;
+        let challenge_id = db.challenge("ACCEPTED", "white", "abc", 0).await;
+        // bob's stake never made it into the ledger
+        sqlx::query("DELETE FROM lightningchess_transaction WHERE username='bob'")
+            .execute(&db.pool).await.unwrap();
+
+        refund(&db.config("", ""), &db.pool, challenge_id, "lichess outage").await.unwrap();
+        assert_eq!(db.status(challenge_id).await, "COMPLETED");
+        assert_eq!(db.ledger(challenge_id).await[1], ("alice".to_string(), "refund".to_string(), 100));
+        assert_eq!((db.balance("alice").await, db.balance("bob").await), (100, 0));
+        let (event_type,) = sqlx::query_as::<_, (String,)>("SELECT event_type FROM lightningchess_event_outbox")
+            .fetch_one(&db.pool).await.unwrap();
+        assert_eq!(event_type, "ChallengeRefunded");
+        db.drop().await;
+    }
+
+    #[test]
+    fn winner_must_be_a_player_or_draw() {
+        let challenge = get_challenge("ACCEPTED");
+        assert_eq!(resolve_winner(&challenge, "user2"), Ok(Some("user2".to_string())));
+        assert_eq!(resolve_winner(&challenge, "draw"), Ok(None));
+        assert_eq!(resolve_winner(&challenge, "user3"), Err(AdminError::UnknownWinner { challenge_id: 1, winner: "user3".to_string() }));
+    }
+}
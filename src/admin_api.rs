@@ -0,0 +1,197 @@
+use std::sync::Arc;
+use axum::{Json, Router, middleware};
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use chrono::NaiveDateTime;
+use reqwest::Client;
+use serde::Serialize;
+use sqlx::{FromRow, Pool, Postgres};
+use tracing::{error, info, warn};
+use crate::config::Config;
+use crate::control::{CONTROL, PAUSABLE_JOBS};
+use crate::db_checks::settle_challenge;
+use crate::models::Challenge;
+use crate::notifications::Notifier;
+use crate::polling::{PollState, POLLING};
+use crate::repository::{active_challenges, get_challenge};
+use crate::supervisor::JobError;
+
+// operator endpoints, nested under /admin when admin_api.token is set.
+// every request needs an Authorization: Bearer <token> header
+
+const LEDGER_LIMIT: i64 = 500;
+
+#[derive(Clone)]
+pub struct AdminState {
+    pool: Pool<Postgres>,
+    config: Arc<Config>,
+    client: Client,
+    notifier: Arc<Notifier>
+}
+
+#[derive(Serialize)]
+struct ActiveChallenge {
+    #[serde(flatten)]
+    challenge: Challenge,
+    polling: Option<PollState>
+}
+
+// ledger rows without the preimage
+#[derive(Serialize, FromRow)]
+struct LedgerEntry {
+    transaction_id: i32,
+    ttype: String,
+    detail: String,
+    amount: i64,
+    state: String,
+    payment_addr: Option<String>,
+    challenge_id: Option<i32>,
+    created_on: Option<NaiveDateTime>
+}
+
+#[derive(Serialize)]
+struct JobState {
+    name: &'static str,
+    paused: bool
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String
+}
+
+fn api_error(status: StatusCode, error: impl ToString) -> Response {
+    (status, Json(ApiError { error: error.to_string() })).into_response()
+}
+
+fn db_error(e: sqlx::Error) -> Response {
+    error!(error = %e, "admin api query failed");
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+}
+
+// compares every byte so the time taken doesn't give away how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn authorized(header: Option<&str>, token: &str) -> bool {
+    match header.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(given) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+        None => false
+    }
+}
+
+async fn require_token<B>(State(state): State<AdminState>, request: Request<B>, next: Next<B>) -> Response {
+    let header = request.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    match &state.config.admin_api.token {
+        Some(token) if authorized(header, token.expose()) => next.run(request).await,
+        _ => {
+            warn!(path = %request.uri().path(), "unauthorized admin api request");
+            api_error(StatusCode::UNAUTHORIZED, "unauthorized")
+        }
+    }
+}
+
+// accepted challenges and ones waiting for acceptance, with what the settlement job last saw on lichess
+async fn get_challenges(State(state): State<AdminState>) -> Result<Json<Vec<ActiveChallenge>>, Response> {
+    let challenges = active_challenges(&state.pool).await
+        .map_err(db_error)?;
+    let challenges = challenges.into_iter()
+        .map(|challenge| ActiveChallenge { polling: POLLING.get(challenge.id), challenge })
+        .collect();
+    Ok(Json(challenges))
+}
+
+// newest first
+async fn get_ledger(State(state): State<AdminState>, Path(username): Path<String>) -> Result<Json<Vec<LedgerEntry>>, Response> {
+    let entries = sqlx::query_as::<_, LedgerEntry>("SELECT transaction_id, ttype, detail, amount, state, payment_addr, challenge_id, created_on FROM lightningchess_transaction WHERE username=$1 ORDER BY transaction_id DESC LIMIT $2")
+        .bind(username)
+        .bind(LEDGER_LIMIT)
+        .fetch_all(&state.pool).await
+        .map_err(db_error)?;
+    Ok(Json(entries))
+}
+
+// runs the settlement for one accepted challenge now instead of waiting for the next pass
+async fn retry_settlement(State(state): State<AdminState>, Path(challenge_id): Path<i32>) -> Result<Json<Option<PollState>>, Response> {
+    let challenge = get_challenge(&state.pool, challenge_id).await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("challenge {} not found", challenge_id)))?;
+    if challenge.status.as_deref() != Some("ACCEPTED") {
+        let status = challenge.status.unwrap_or_default();
+        return Err(api_error(StatusCode::CONFLICT, format!("challenge {} is {}, only accepted challenges are settled", challenge_id, status)));
+    }
+
+    info!(challenge_id, "settlement retried by operator");
+    match settle_challenge(&state.pool, &state.config, &state.client, &state.notifier, &challenge, false).await {
+        Ok(_) => {
+            POLLING.record_success(challenge_id);
+            Ok(Json(POLLING.get(challenge_id)))
+        }
+        Err(e) => {
+            POLLING.record_error(challenge_id, e.to_string());
+            // an error that won't go away by retrying needs someone to look at the challenge
+            let status = if e.is_retryable() { StatusCode::BAD_GATEWAY } else { StatusCode::UNPROCESSABLE_ENTITY };
+            Err(api_error(status, e))
+        }
+    }
+}
+
+async fn get_jobs() -> Json<Vec<JobState>> {
+    Json(PAUSABLE_JOBS.into_iter().map(|name| JobState { name, paused: CONTROL.is_paused(name) }).collect())
+}
+
+async fn pause_job(Path(name): Path<String>) -> Response {
+    if CONTROL.pause(&name) {
+        info!(job = %name, "job paused by operator");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        api_error(StatusCode::NOT_FOUND, format!("no job named {}", name))
+    }
+}
+
+async fn resume_job(Path(name): Path<String>) -> Response {
+    if CONTROL.resume(&name) {
+        info!(job = %name, "job resumed by operator");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        api_error(StatusCode::NOT_FOUND, format!("no job named {}", name))
+    }
+}
+
+pub fn router(config: Arc<Config>, pool: Pool<Postgres>) -> Result<Router, JobError> {
+    let client = Client::builder()
+        .timeout(config.lichess.request_timeout())
+        .build()
+        .map_err(|e| JobError::Fatal(format!("can't build lichess client: {}", e)))?;
+    let notifier = Arc::new(Notifier::from_config(&config)?);
+    let state = AdminState { pool, config, client, notifier };
+
+    Ok(Router::new()
+        .route("/challenges", get(get_challenges))
+        .route("/challenges/:id/retry", post(retry_settlement))
+        .route("/users/:username/ledger", get(get_ledger))
+        .route("/jobs", get(get_jobs))
+        .route("/jobs/:name/pause", post(pause_job))
+        .route("/jobs/:name/resume", post(resume_job))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_the_bearer_token() {
+        let token = "0123456789abcdef";
+        assert!(authorized(Some("Bearer 0123456789abcdef"), token));
+        assert!(!authorized(Some("Bearer 0123456789abcdeF"), token));
+        assert!(!authorized(Some("Bearer 0123456789abcde"), token));
+        assert!(!authorized(Some("0123456789abcdef"), token));
+        assert!(!authorized(None, token));
+    }
+}
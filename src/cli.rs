@@ -0,0 +1,157 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+#[derive(Parser, Debug)]
+#[command(name = "lightningchess-jobs", about = "Settles lightningchess challenges and credits lightning deposits")]
+pub struct Cli {
+    // runs the default jobs when left out, like the binary always did
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Run jobs until SIGTERM. the http server for metrics and health always runs
+    Run {
+        /// Only run these jobs. repeat or comma separate to run several
+        #[arg(long, value_enum, value_delimiter = ',')]
+        only: Vec<Job>
+    },
+    /// Settle finished games once and exit
+    SettleOnce {
+        #[command(flatten)]
+        dry_run: DryRun
+    },
+    /// Expire challenges that were never accepted once and exit
+    ExpireOnce {
+        #[command(flatten)]
+        dry_run: DryRun
+    },
+    /// Reconcile open invoices with lnd once and exit
+    ReconcileOnce,
+    /// Apply database migrations and exit
+    Migrate,
+    /// Settle an accepted challenge by hand, charging the usual fee
+    Settle {
+        challenge_id: i32,
+        /// A player's username, or draw
+        #[arg(long)]
+        winner: String,
+        /// Why, for the ledger
+        #[arg(long)]
+        note: String
+    },
+    /// Return the stakes of a challenge without a fee
+    Refund {
+        challenge_id: i32,
+        /// Why, for the ledger
+        #[arg(long)]
+        note: String
+    },
+    /// Cancel a challenge and return what each player paid in for it
+    Void {
+        challenge_id: i32,
+        /// Why, for the ledger
+        #[arg(long)]
+        note: String
+    }
+}
+
+#[derive(Args, Debug, PartialEq, Eq)]
+pub struct DryRun {
+    /// Print the ledger rows, balance changes and status changes the pass would make and roll them back
+    #[arg(long)]
+    pub dry_run: bool,
+    /// How to print the dry run report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Table, requires = "dry_run")]
+    pub format: ReportFormat
+}
+
+impl DryRun {
+    // the report format, if this is a dry run
+    pub fn report(&self) -> Option<ReportFormat> {
+        self.dry_run.then_some(self.format)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Json
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Job {
+    Settle,
+    Expire,
+    Invoices,
+    Reconcile,
+    Notifications,
+    Events
+}
+
+const ALL_JOBS: [Job; 6] = [Job::Settle, Job::Expire, Job::Invoices, Job::Reconcile, Job::Notifications, Job::Events];
+// reconciliation only looks invoices up so far, so it runs when asked for with --only
+const DEFAULT_JOBS: [Job; 5] = [Job::Settle, Job::Expire, Job::Invoices, Job::Notifications, Job::Events];
+
+impl Cli {
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Run { only: Vec::new() })
+    }
+}
+
+// no --only means the default jobs
+pub fn selected_jobs(only: &[Job]) -> Vec<Job> {
+    if only.is_empty() {
+        DEFAULT_JOBS.to_vec()
+    } else {
+        ALL_JOBS.into_iter().filter(|job| only.contains(job)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Command {
+        Cli::try_parse_from(args).unwrap().command()
+    }
+
+    #[test]
+    fn runs_everything_by_default() {
+        assert_eq!(parse(&["lightningchess-jobs"]), Command::Run { only: vec![] });
+        assert_eq!(selected_jobs(&[]), DEFAULT_JOBS.to_vec());
+        assert!(!selected_jobs(&[]).contains(&Job::Reconcile));
+        assert_eq!(selected_jobs(&[Job::Reconcile]), vec![Job::Reconcile]);
+    }
+
+    #[test]
+    fn run_only() {
+        let command = parse(&["lightningchess-jobs", "run", "--only", "settle,invoices", "--only", "expire"]);
+        assert_eq!(command, Command::Run { only: vec![Job::Settle, Job::Invoices, Job::Expire] });
+        assert_eq!(selected_jobs(&[Job::Settle, Job::Invoices, Job::Expire]), vec![Job::Settle, Job::Expire, Job::Invoices]);
+    }
+
+    #[test]
+    fn one_shot_commands() {
+        assert_eq!(parse(&["lightningchess-jobs", "settle-once"]), Command::SettleOnce { dry_run: DryRun { dry_run: false, format: ReportFormat::Table } });
+        assert_eq!(parse(&["lightningchess-jobs", "reconcile-once"]), Command::ReconcileOnce);
+        assert_eq!(parse(&["lightningchess-jobs", "migrate"]), Command::Migrate);
+        assert!(Cli::try_parse_from(["lightningchess-jobs", "run", "--only", "payouts"]).is_err());
+    }
+
+    #[test]
+    fn dry_run() {
+        let command = parse(&["lightningchess-jobs", "expire-once", "--dry-run", "--format", "json"]);
+        assert_eq!(command, Command::ExpireOnce { dry_run: DryRun { dry_run: true, format: ReportFormat::Json } });
+        // a format without --dry-run would look like it did something
+        assert!(Cli::try_parse_from(["lightningchess-jobs", "settle-once", "--format", "json"]).is_err());
+    }
+
+    #[test]
+    fn admin_commands() {
+        let command = parse(&["lightningchess-jobs", "settle", "12", "--winner", "draw", "--note", "lichess outage"]);
+        assert_eq!(command, Command::Settle { challenge_id: 12, winner: "draw".to_string(), note: "lichess outage".to_string() });
+        // the note is required
+        assert!(Cli::try_parse_from(["lightningchess-jobs", "void", "12"]).is_err());
+    }
+}
@@ -0,0 +1,648 @@
+use std::{env, fmt, fs};
+use std::error;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::time::Duration;
+use crate::sats::BASIS_POINTS_DENOMINATOR;
+
+// 2% fee charged to each player
+const DEFAULT_FEE_BASIS_POINTS: i64 = 200;
+// used when a challenge doesn't set its own expire_after. 30 min
+const DEFAULT_EXPIRE_AFTER_SECONDS: i32 = 1_800;
+// safety net in case a wakeup notification is missed
+const DEFAULT_POLL_INTERVAL_SECONDS: u64 = 60;
+// caps how long one settlement, expiry or reconciliation pass keeps going before leaving the rest
+// of its backlog for the next one
+const DEFAULT_PASS_BUDGET_SECONDS: u64 = 300;
+// accepted challenges checked against lichess at the same time. lichess asks api clients to make
+// one request at a time, so raise it only alongside max_requests_per_second. settling is bound by
+// the lichess rate, not the workers: each accepted challenge is one game export, so at the default
+// rate a pass checks about pass_budget_seconds of them and 1000 active games take about 17 minutes,
+// carried over several passes by the scan cursor. settling them in seconds isn't possible within
+// lichess's limits
+const DEFAULT_SETTLEMENT_WORKERS: usize = 1;
+// shared by every worker and the lichess inbox sink. lichess asks for a minute's pause after a 429
+// on top of this
+const DEFAULT_LICHESS_MAX_REQUESTS_PER_SECOND: u32 = 1;
+// how long lichess 404s an accepted challenge's game before it's refunded as unplayed. timed rather
+// than counted since wakeups can run many passes in a few seconds. 30 min
+const DEFAULT_MISSING_GAME_AFTER_SECONDS: u64 = 1_800;
+// on top of the pass budget and poll interval before readiness counts a settle or expire job as
+// stuck. covers the minute lichess asks for after a 429
+const DB_CHECKS_STALE_SLACK_SECONDS: u64 = 120;
+// docker stop sends SIGKILL 10 seconds after SIGTERM
+const DEFAULT_SHUTDOWN_DEADLINE_SECONDS: u64 = 8;
+const MIN_ADMIN_API_TOKEN_LENGTH: usize = 16;
+
+// loaded once at startup from the toml file in CONFIG_FILE, if set, with env vars taking precedence.
+// everything is checked before any job starts so a bad value fails fast
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub db: DbConfig,
+    pub http: HttpConfig,
+    pub admin_api: AdminApiConfig,
+    pub settlement: SettlementConfig,
+    pub lnd: LndConfig,
+    pub lichess: LichessConfig,
+    pub notifications: NotificationsConfig,
+    pub events: EventsConfig,
+    pub reconcile: ReconcileConfig,
+    pub shutdown_deadline_seconds: u64
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub url: Secret,
+    // shared by every job. each settlement worker holds one while it writes
+    pub max_connections: u32,
+    // kept open while idle
+    pub min_connections: u32,
+    // how long a query waits for a free connection before failing
+    pub acquire_timeout_seconds: u64,
+    // postgres cancels statements that run longer. 0 for no limit
+    pub statement_timeout_seconds: u64,
+    // apply migrations/ before the jobs start. the migrate command does it on its own
+    pub migrate_on_startup: bool
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub addr: SocketAddr
+}
+
+// the operator api under /admin is only served when a token is set
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminApiConfig {
+    pub token: Option<Secret>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettlementConfig {
+    // collects the fees
+    pub admin_account: String,
+    pub fee_basis_points: i64,
+    pub poll_interval_seconds: u64,
+    pub default_expire_after_seconds: i32,
+    pub missing_game_after_seconds: u64,
+    pub pass_budget_seconds: u64,
+    pub workers: usize
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LndConfig {
+    pub url: String,
+    pub macaroon: Secret,
+    // the invoice stream stays open so this only bounds connecting to it
+    pub request_timeout_seconds: u64
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LichessConfig {
+    pub url: String,
+    pub request_timeout_seconds: u64,
+    pub max_requests_per_second: u32,
+    // enables the lichess inbox notification sink
+    pub message_token: Option<Secret>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub poll_interval_seconds: u64,
+    // bounds each webhook, lichess message and smtp send
+    pub request_timeout_seconds: u64,
+    pub webhook_url: Option<String>,
+    pub smtp: Option<SmtpConfig>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub username: String,
+    pub password: Secret,
+    pub from: String
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    pub poll_interval_seconds: u64
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconcileConfig {
+    pub poll_interval_seconds: u64,
+    pub pass_budget_seconds: u64
+}
+
+// keeps credentials out of logs and Debug output
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            db: DbConfig::default(),
+            http: HttpConfig::default(),
+            admin_api: AdminApiConfig::default(),
+            settlement: SettlementConfig::default(),
+            lnd: LndConfig::default(),
+            lichess: LichessConfig::default(),
+            notifications: NotificationsConfig::default(),
+            events: EventsConfig::default(),
+            reconcile: ReconcileConfig::default(),
+            shutdown_deadline_seconds: DEFAULT_SHUTDOWN_DEADLINE_SECONDS
+        }
+    }
+}
+
+// points the jobs at a test database and local stubs for lichess and lnd
+#[cfg(test)]
+impl Config {
+    pub fn for_tests(db_url: &str, lichess_url: &str, lnd_url: &str) -> Config {
+        let mut config = Config {
+            db: DbConfig { url: Secret(db_url.to_string()), max_connections: 4, migrate_on_startup: false, ..DbConfig::default() },
+            ..Config::default()
+        };
+        config.settlement.admin_account = "admin".to_string();
+        config.lichess.url = lichess_url.to_string();
+        // the stubs don't rate limit, and a few workers keep the concurrent paths covered
+        config.lichess.max_requests_per_second = 1_000;
+        config.settlement.workers = 2;
+        config.lnd.url = lnd_url.to_string();
+        config.lnd.macaroon = Secret("macaroon".to_string());
+        config
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            url: Secret::default(),
+            max_connections: 16,
+            min_connections: 1,
+            acquire_timeout_seconds: 30,
+            statement_timeout_seconds: 60,
+            migrate_on_startup: true
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig { addr: SocketAddr::from(([0, 0, 0, 0], 8080)) }
+    }
+}
+
+impl Default for SettlementConfig {
+    fn default() -> Self {
+        SettlementConfig {
+            admin_account: "".to_string(),
+            fee_basis_points: DEFAULT_FEE_BASIS_POINTS,
+            poll_interval_seconds: DEFAULT_POLL_INTERVAL_SECONDS,
+            default_expire_after_seconds: DEFAULT_EXPIRE_AFTER_SECONDS,
+            missing_game_after_seconds: DEFAULT_MISSING_GAME_AFTER_SECONDS,
+            pass_budget_seconds: DEFAULT_PASS_BUDGET_SECONDS,
+            workers: DEFAULT_SETTLEMENT_WORKERS
+        }
+    }
+}
+
+impl Default for LndConfig {
+    fn default() -> Self {
+        LndConfig {
+            url: "https://lightningchess.m.voltageapp.io:8080".to_string(),
+            macaroon: Secret::default(),
+            request_timeout_seconds: 30
+        }
+    }
+}
+
+impl Default for LichessConfig {
+    fn default() -> Self {
+        LichessConfig {
+            url: "https://lichess.org".to_string(),
+            request_timeout_seconds: 30,
+            max_requests_per_second: DEFAULT_LICHESS_MAX_REQUESTS_PER_SECOND,
+            message_token: None
+        }
+    }
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig { poll_interval_seconds: 10, request_timeout_seconds: 30, webhook_url: None, smtp: None }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig { poll_interval_seconds: 5 }
+    }
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        // a backup for the invoice stream so it doesn't need to run often
+        ReconcileConfig { poll_interval_seconds: 600, pass_budget_seconds: DEFAULT_PASS_BUDGET_SECONDS }
+    }
+}
+
+impl DbConfig {
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_seconds)
+    }
+}
+
+impl SettlementConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_seconds)
+    }
+    pub fn pass_budget(&self) -> Duration {
+        Duration::from_secs(self.pass_budget_seconds)
+    }
+    pub fn missing_game_after(&self) -> Duration {
+        Duration::from_secs(self.missing_game_after_seconds)
+    }
+    // a healthy settle or expire job finishes a pass within the pass budget, then waits up to the
+    // poll interval before the next
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.pass_budget_seconds
+            .saturating_add(self.poll_interval_seconds)
+            .saturating_add(DB_CHECKS_STALE_SLACK_SECONDS))
+    }
+}
+
+impl LndConfig {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_seconds)
+    }
+}
+
+impl LichessConfig {
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_seconds)
+    }
+
+    pub fn request_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.max_requests_per_second
+    }
+}
+
+impl NotificationsConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_seconds)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_seconds)
+    }
+}
+
+impl EventsConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_seconds)
+    }
+}
+
+impl ReconcileConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_seconds)
+    }
+    pub fn pass_budget(&self) -> Duration {
+        Duration::from_secs(self.pass_budget_seconds)
+    }
+}
+
+// every problem found, so they can all be fixed in one go
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<String>
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config: {}", self.problems.join("; "))
+    }
+}
+
+impl error::Error for ConfigError {}
+
+fn override_string(vars: &impl Fn(&str) -> Option<String>, name: &str, target: &mut String) {
+    if let Some(value) = vars(name) {
+        *target = value;
+    }
+}
+
+fn override_parsed<T: FromStr>(vars: &impl Fn(&str) -> Option<String>, name: &str, target: &mut T, problems: &mut Vec<String>)
+    where T::Err: fmt::Display {
+    if let Some(value) = vars(name) {
+        match value.parse::<T>() {
+            Ok(parsed) => *target = parsed,
+            Err(e) => problems.push(format!("{} is not valid: {}", name, e))
+        }
+    }
+}
+
+fn check_url(name: &str, url: &str, problems: &mut Vec<String>) {
+    if let Err(e) = Url::parse(url) {
+        problems.push(format!("{} is not a valid url: {}", name, e));
+    }
+}
+
+impl Config {
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_secs(self.shutdown_deadline_seconds)
+    }
+
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| ConfigError { problems: vec![format!("can't read config file {}: {}", path, e)] })?;
+                Config::from_toml(&contents)?
+            }
+            Err(_) => Config::default()
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        config.validate()
+    }
+
+    fn from_toml(contents: &str) -> Result<Config, ConfigError> {
+        toml::from_str(contents).map_err(|e| ConfigError { problems: vec![format!("can't parse config file: {}", e)] })
+    }
+
+    // env var names predate the config file and are kept as they were
+    fn apply_env(&mut self, vars: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if let Some(url) = vars("DB_URL") {
+            self.db.url = Secret(url);
+        }
+        override_parsed(&vars, "DB_MAX_CONNECTIONS", &mut self.db.max_connections, &mut problems);
+        override_parsed(&vars, "DB_MIN_CONNECTIONS", &mut self.db.min_connections, &mut problems);
+        override_parsed(&vars, "DB_ACQUIRE_TIMEOUT_SECONDS", &mut self.db.acquire_timeout_seconds, &mut problems);
+        override_parsed(&vars, "DB_STATEMENT_TIMEOUT_SECONDS", &mut self.db.statement_timeout_seconds, &mut problems);
+        override_parsed(&vars, "DB_MIGRATE_ON_STARTUP", &mut self.db.migrate_on_startup, &mut problems);
+        override_parsed(&vars, "HTTP_ADDR", &mut self.http.addr, &mut problems);
+        if let Some(token) = vars("ADMIN_API_TOKEN") {
+            self.admin_api.token = Some(Secret(token));
+        }
+
+        override_string(&vars, "ADMIN_ACCOUNT", &mut self.settlement.admin_account);
+        override_parsed(&vars, "FEE_BASIS_POINTS", &mut self.settlement.fee_basis_points, &mut problems);
+        override_parsed(&vars, "POLL_INTERVAL_SECONDS", &mut self.settlement.poll_interval_seconds, &mut problems);
+        override_parsed(&vars, "CHALLENGE_EXPIRE_AFTER_SECONDS", &mut self.settlement.default_expire_after_seconds, &mut problems);
+        override_parsed(&vars, "MISSING_GAME_AFTER_SECONDS", &mut self.settlement.missing_game_after_seconds, &mut problems);
+        override_parsed(&vars, "PASS_BUDGET_SECONDS", &mut self.settlement.pass_budget_seconds, &mut problems);
+        override_parsed(&vars, "SETTLEMENT_WORKERS", &mut self.settlement.workers, &mut problems);
+
+        override_string(&vars, "LND_URL", &mut self.lnd.url);
+        if let Some(macaroon) = vars("LND_MACAROON") {
+            self.lnd.macaroon = Secret(macaroon);
+        }
+        override_parsed(&vars, "LND_TIMEOUT_SECONDS", &mut self.lnd.request_timeout_seconds, &mut problems);
+
+        override_string(&vars, "LICHESS_URL", &mut self.lichess.url);
+        override_parsed(&vars, "LICHESS_TIMEOUT_SECONDS", &mut self.lichess.request_timeout_seconds, &mut problems);
+        override_parsed(&vars, "LICHESS_MAX_REQUESTS_PER_SECOND", &mut self.lichess.max_requests_per_second, &mut problems);
+        if let Some(token) = vars("LICHESS_MESSAGE_TOKEN") {
+            self.lichess.message_token = Some(Secret(token));
+        }
+
+        override_parsed(&vars, "NOTIFY_TIMEOUT_SECONDS", &mut self.notifications.request_timeout_seconds, &mut problems);
+        if let Some(url) = vars("NOTIFY_WEBHOOK_URL") {
+            self.notifications.webhook_url = Some(url);
+        }
+        if ["SMTP_HOST", "SMTP_USERNAME", "SMTP_PASSWORD", "SMTP_FROM"].iter().any(|name| vars(name).is_some()) {
+            let smtp = self.notifications.smtp.get_or_insert_with(SmtpConfig::default);
+            override_string(&vars, "SMTP_HOST", &mut smtp.host);
+            override_string(&vars, "SMTP_USERNAME", &mut smtp.username);
+            if let Some(password) = vars("SMTP_PASSWORD") {
+                smtp.password = Secret(password);
+            }
+            override_string(&vars, "SMTP_FROM", &mut smtp.from);
+        }
+
+        override_parsed(&vars, "SHUTDOWN_DEADLINE_SECONDS", &mut self.shutdown_deadline_seconds, &mut problems);
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigError { problems }) }
+    }
+
+    fn validate(mut self) -> Result<Config, ConfigError> {
+        let mut problems = Vec::new();
+        if self.db.url.expose().is_empty() {
+            problems.push("db url (DB_URL) is required".to_string());
+        }
+        if self.db.max_connections == 0 {
+            problems.push("db max_connections must be at least 1".to_string());
+        }
+        if self.db.min_connections > self.db.max_connections {
+            problems.push("db min_connections can't be more than max_connections".to_string());
+        }
+        // the other jobs and the wakeup listener need connections while the workers hold theirs
+        if self.db.max_connections as usize <= self.settlement.workers {
+            problems.push("db max_connections must be more than settlement workers".to_string());
+        }
+
+        if self.admin_api.token.as_ref().is_some_and(|token| token.expose().len() < MIN_ADMIN_API_TOKEN_LENGTH) {
+            problems.push(format!("admin api token must be at least {} characters", MIN_ADMIN_API_TOKEN_LENGTH));
+        }
+
+        if self.settlement.admin_account.is_empty() {
+            problems.push("admin account (ADMIN_ACCOUNT) is required".to_string());
+        }
+        if !(0..=BASIS_POINTS_DENOMINATOR).contains(&self.settlement.fee_basis_points) {
+            problems.push(format!("fee_basis_points must be between 0 and {}", BASIS_POINTS_DENOMINATOR));
+        }
+        if self.settlement.default_expire_after_seconds <= 0 {
+            problems.push("default_expire_after_seconds must be positive".to_string());
+        }
+        if self.settlement.workers == 0 {
+            problems.push("settlement workers must be at least 1".to_string());
+        }
+        if self.lichess.max_requests_per_second == 0 {
+            problems.push("lichess max_requests_per_second must be at least 1".to_string());
+        }
+        for (name, seconds) in [
+            ("settlement poll_interval_seconds", self.settlement.poll_interval_seconds),
+            ("notifications poll_interval_seconds", self.notifications.poll_interval_seconds),
+            ("events poll_interval_seconds", self.events.poll_interval_seconds),
+            ("reconcile poll_interval_seconds", self.reconcile.poll_interval_seconds),
+            ("db acquire_timeout_seconds", self.db.acquire_timeout_seconds),
+            ("settlement pass_budget_seconds", self.settlement.pass_budget_seconds),
+            ("settlement missing_game_after_seconds", self.settlement.missing_game_after_seconds),
+            ("reconcile pass_budget_seconds", self.reconcile.pass_budget_seconds),
+            ("lnd request_timeout_seconds", self.lnd.request_timeout_seconds),
+            ("lichess request_timeout_seconds", self.lichess.request_timeout_seconds),
+            ("notifications request_timeout_seconds", self.notifications.request_timeout_seconds)
+        ] {
+            if seconds == 0 {
+                problems.push(format!("{} must be positive", name));
+            }
+        }
+
+        if self.lnd.macaroon.expose().is_empty() {
+            problems.push("lnd macaroon (LND_MACAROON) is required".to_string());
+        }
+        // urls are joined with paths so a trailing slash would double up
+        self.lnd.url = self.lnd.url.trim_end_matches('/').to_string();
+        self.lichess.url = self.lichess.url.trim_end_matches('/').to_string();
+        check_url("lnd url", &self.lnd.url, &mut problems);
+        check_url("lichess url", &self.lichess.url, &mut problems);
+        if let Some(url) = &self.notifications.webhook_url {
+            check_url("webhook url", url, &mut problems);
+        }
+        if let Some(smtp) = &self.notifications.smtp {
+            if smtp.host.is_empty() || smtp.username.is_empty() || smtp.password.expose().is_empty() || smtp.from.is_empty() {
+                problems.push("smtp needs host, username, password and from".to_string());
+            }
+        }
+
+        if problems.is_empty() { Ok(self) } else { Err(ConfigError { problems }) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn required() -> Vec<(&'static str, &'static str)> {
+        vec![("DB_URL", "postgres://localhost/lightningchess"), ("ADMIN_ACCOUNT", "admin"), ("LND_MACAROON", "abc")]
+    }
+
+    #[test]
+    fn defaults_with_required_env() {
+        let mut config = Config::default();
+        config.apply_env(env_vars(&required())).unwrap();
+        let config = config.validate().unwrap();
+        assert_eq!(config.settlement.fee_basis_points, 200);
+        assert_eq!(config.settlement.poll_interval(), Duration::from_secs(60));
+        assert_eq!(config.http.addr.to_string(), "0.0.0.0:8080");
+        assert_eq!(config.lichess.url, "https://lichess.org");
+        assert_eq!(config.notifications.smtp, None);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config = Config::from_toml(r#"
+            [db]
+            url = "postgres://file/lightningchess"
+            max_connections = 12
+
+            [settlement]
+            admin_account = "file_admin"
+            fee_basis_points = 150
+
+            [lichess]
+            url = "http://localhost:9000/"
+        "#).unwrap();
+        let mut vars = required();
+        vars.push(("FEE_BASIS_POINTS", "100"));
+        config.apply_env(env_vars(&vars)).unwrap();
+        let config = config.validate().unwrap();
+        assert_eq!(config.db.url.expose(), "postgres://localhost/lightningchess");
+        assert_eq!(config.db.max_connections, 12);
+        assert_eq!(config.settlement.admin_account, "admin");
+        assert_eq!(config.settlement.fee_basis_points, 100);
+        assert_eq!(config.lichess.url, "http://localhost:9000");
+    }
+
+    #[test]
+    fn example_file_matches_defaults() {
+        let config = Config::from_toml(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn unknown_keys_rejected() {
+        assert!(Config::from_toml("[settlement]\nfee_percent = 2").is_err());
+    }
+
+    #[test]
+    fn unparseable_env() {
+        let mut config = Config::default();
+        let error = config.apply_env(env_vars(&[("FEE_BASIS_POINTS", "2%"), ("HTTP_ADDR", "localhost")])).unwrap_err();
+        assert_eq!(error.problems.len(), 2);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config = Config::default();
+        config.settlement.fee_basis_points = 20_000;
+        config.apply_env(env_vars(&[("SMTP_HOST", "smtp.example.com")])).unwrap();
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.problems, vec![
+            "db url (DB_URL) is required".to_string(),
+            "admin account (ADMIN_ACCOUNT) is required".to_string(),
+            "fee_basis_points must be between 0 and 10000".to_string(),
+            "lnd macaroon (LND_MACAROON) is required".to_string(),
+            "smtp needs host, username, password and from".to_string()
+        ]);
+    }
+
+    #[test]
+    fn short_admin_api_token() {
+        let mut config = Config::default();
+        let mut vars = required();
+        vars.push(("ADMIN_API_TOKEN", "secret"));
+        config.apply_env(env_vars(&vars)).unwrap();
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.problems, vec!["admin api token must be at least 16 characters".to_string()]);
+    }
+
+    #[test]
+    fn pool_leaves_room_beside_the_workers() {
+        let mut config = Config::default();
+        let mut vars = required();
+        vars.push(("DB_MAX_CONNECTIONS", "4"));
+        vars.push(("SETTLEMENT_WORKERS", "4"));
+        config.apply_env(env_vars(&vars)).unwrap();
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.problems, vec!["db max_connections must be more than settlement workers".to_string()]);
+    }
+
+    #[test]
+    fn db_checks_stale_after_covers_a_pass_and_the_poll_interval() {
+        let mut settlement = SettlementConfig::default();
+        assert_eq!(settlement.stale_after(), Duration::from_secs(300 + 60 + 120));
+        settlement.poll_interval_seconds = 900;
+        assert_eq!(settlement.stale_after(), Duration::from_secs(300 + 900 + 120));
+    }
+
+    #[test]
+    fn secrets_not_in_debug() {
+        let mut config = Config::default();
+        config.apply_env(env_vars(&required())).unwrap();
+        assert!(!format!("{:?}", config).contains("abc"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use tokio::sync::watch;
+use crate::shutdown::Shutdown;
+
+// names the supervisor runs the jobs under. all of them can be paused from the admin api
+pub const SUBSCRIBE_INVOICES: &str = "subscribe_invoices";
+pub const DELIVER_NOTIFICATIONS: &str = "deliver_notifications";
+pub const RELAY_EVENTS: &str = "relay_events";
+pub const RECONCILE_INVOICES: &str = "reconcile_invoices";
+pub const SETTLE_CHALLENGES: &str = "settle_challenges";
+pub const EXPIRE_CHALLENGES: &str = "expire_challenges";
+pub const PAUSABLE_JOBS: [&str; 6] = [SUBSCRIBE_INVOICES, DELIVER_NOTIFICATIONS, RELAY_EVENTS, RECONCILE_INVOICES, SETTLE_CHALLENGES, EXPIRE_CHALLENGES];
+
+// jobs check in between passes. a paused job finishes what it is doing and then waits to be resumed
+pub struct JobControl {
+    paused: watch::Sender<HashSet<&'static str>>
+}
+
+pub static CONTROL: LazyLock<JobControl> = LazyLock::new(JobControl::new);
+
+impl JobControl {
+    fn new() -> JobControl {
+        JobControl { paused: watch::channel(HashSet::new()).0 }
+    }
+
+    fn job_name(job: &str) -> Option<&'static str> {
+        PAUSABLE_JOBS.into_iter().find(|name| *name == job)
+    }
+
+    // false if there is no such job
+    pub fn pause(&self, job: &str) -> bool {
+        match JobControl::job_name(job) {
+            Some(name) => {
+                self.paused.send_modify(|paused| { paused.insert(name); });
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn resume(&self, job: &str) -> bool {
+        match JobControl::job_name(job) {
+            Some(name) => {
+                self.paused.send_modify(|paused| { paused.remove(name); });
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn is_paused(&self, job: &str) -> bool {
+        self.paused.borrow().contains(job)
+    }
+
+    // resolves once the job is paused
+    pub async fn paused(&self, job: &str) {
+        let mut receiver = self.paused.subscribe();
+        let _ = receiver.wait_for(|paused| paused.contains(job)).await;
+    }
+
+    // returns straight away unless the job is paused, then once it is resumed or shutdown is requested
+    pub async fn wait_while_paused(&self, job: &str, shutdown: &Shutdown) {
+        let mut receiver = self.paused.subscribe();
+        tokio::select! {
+            _ = receiver.wait_for(|paused| !paused.contains(job)) => (),
+            _ = shutdown.requested() => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let control = JobControl::new();
+        let (_trigger, shutdown) = Shutdown::new();
+        assert!(!control.pause("http"));
+        assert!(control.pause(SETTLE_CHALLENGES));
+        assert!(control.is_paused(SETTLE_CHALLENGES));
+        assert!(!control.is_paused(RELAY_EVENTS));
+        control.paused(SETTLE_CHALLENGES).await;
+        assert!(timeout(Duration::from_millis(10), control.wait_while_paused(SETTLE_CHALLENGES, &shutdown)).await.is_err());
+
+        assert!(control.resume(SETTLE_CHALLENGES));
+        control.wait_while_paused(SETTLE_CHALLENGES, &shutdown).await;
+    }
+}
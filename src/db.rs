@@ -0,0 +1,62 @@
+use std::str::FromStr;
+use sqlx::{Pool, Postgres};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+use crate::config::DbConfig;
+use crate::supervisor::JobError;
+
+// the database is often still starting when the jobs are, so connecting is retried
+const CONNECT_ATTEMPTS: u32 = 8;
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+fn pool_options(config: &DbConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout())
+}
+
+// the one pool every job shares, created in main
+pub async fn connect(config: &DbConfig) -> Result<Pool<Postgres>, JobError> {
+    let connect_options = PgConnectOptions::from_str(config.url.expose())
+        .map_err(|e| JobError::Fatal(format!("can't parse db url: {}", e)))?
+        .options([("statement_timeout", format!("{}s", config.statement_timeout_seconds))]);
+    let mut backoff = INITIAL_CONNECT_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match pool_options(config).connect_with(connect_options.clone()).await {
+            Ok(pool) => {
+                info!(max_connections = config.max_connections, "connected to db");
+                return Ok(pool);
+            }
+            Err(e) if attempt < CONNECT_ATTEMPTS => {
+                warn!(error = %e, attempt, ?backoff, "can't connect to db. retrying");
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => return Err(JobError::Fatal(format!("can't connect to db after {} attempts: {}", attempt, e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_db;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn cancels_slow_statements() {
+        let db = test_db().await;
+        let mut config = db.config("", "").db;
+        config.statement_timeout_seconds = 1;
+        let pool = connect(&config).await.unwrap();
+        assert!(sqlx::query("SELECT pg_sleep(0.1)").execute(&pool).await.is_ok());
+        assert!(sqlx::query("SELECT pg_sleep(2)").execute(&pool).await.is_err());
+        pool.close().await;
+        db.drop().await;
+    }
+}
@@ -1,327 +1,750 @@
-use std::{env};
-use std::collections::HashMap;
-use chrono::NaiveDateTime;
-use tokio::time::{sleep, Duration};
+use std::sync::Arc;
+use futures::{stream, FutureExt, StreamExt};
+use tokio::time::{sleep, Duration, Instant};
 use reqwest::{Client};
 use sqlx::{Error, Pool, Postgres};
-use sqlx::postgres::{PgPoolOptions, PgQueryResult};
-use chrono::prelude::Utc;
-use crate::models::{Challenge, LightningChessResult, LichessExportGameResponse};
-
-fn get_winner_username(challenge: &Challenge, winner: &str) -> String {
-    // determine if user who created the challenge won
-    let creator_won_black = winner == "black" && challenge.color.as_ref().unwrap() == "black";
-    let creator_won_white = winner == "white" && challenge.color.as_ref().unwrap() == "white";
-    return if creator_won_black || creator_won_white {
-        &challenge.username
-    } else {
-        &challenge.opp_username
-    }.to_string()
+use sqlx::postgres::PgListener;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
+use crate::config::Config;
+use crate::control::CONTROL;
+use crate::error::{LightningChessError, LightningChessResult};
+use crate::models::{Challenge, LichessExportGameResponse};
+use crate::{health, metrics};
+use crate::events::record_event;
+use crate::notifications::Notifier;
+use crate::polling::POLLING;
+use crate::repository::{PAGE_SIZE, accepted_challenges, add_to_balance, change_status, expired_challenges, get_challenge_ledger, insert_ledger_row, lock_challenge, pending_challenges};
+use crate::settlement::{calculate_payouts, format_fee_percent, lichess_outcome, plan_refund, plan_settlement, Outcome, Refund, SettlementPlan};
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
+use crate::throttle::{LICHESS_RATE_LIMIT_BACKOFF, LICHESS_THROTTLE};
+
+// the web app NOTIFYs these so the checks run right away instead of on the next tick
+const WAKEUP_CHANNELS: [&str; 1] = ["challenge_accepted"];
+
+// writes a plan from the settlement planners. the caller owns the transaction and commits it,
+// or rolls it back in a dry run
+#[instrument(skip_all, fields(challenge_id = plan.challenge_id, status = %plan.status.to))]
+pub async fn apply_plan(tx: &mut sqlx::Transaction<'_, Postgres>,
+                        notifier: &Notifier,
+                        challenge: &Challenge,
+                        plan: &SettlementPlan) -> Result<(), Error> {
+    for insert in plan.ledger.iter() {
+        debug!(username = %insert.username, ttype = %insert.ttype, amount = %insert.amount, "insert transaction");
+        insert_ledger_row(tx, challenge, insert).await?;
+    }
+    for change in plan.balances.iter() {
+        debug!(username = %change.username, amount = %change.amount, "update balance");
+        add_to_balance(tx, change).await?;
+    }
+    for (username, event) in plan.notifications.iter() {
+        notifier.enqueue(tx, username, event).await?;
+    }
+    change_status(tx, plan.challenge_id, &plan.status).await?;
+    record_event(tx, &plan.event).await?;
+    Ok(())
 }
 
-fn calculate_fee_per_person(challenge: &Challenge) -> i64 {
-    let initial_fee: f64 = (challenge.sats.unwrap() as f64) * 0.02;
-    initial_fee.floor() as i64
+// counts the sats a committed plan paid out and collected in fees
+fn record_plan_metrics(plan: &SettlementPlan) {
+    for insert in plan.ledger.iter() {
+        if insert.ttype == "fee" {
+            metrics::record_fee(insert.amount);
+        } else {
+            metrics::record_payout(insert.amount);
+        }
+    }
 }
 
-async fn insert_tx(tx: &mut sqlx::Transaction<'_, Postgres>,
-                            username: &String,
-                            ttype: &String,
-                            detail: &String,
-                            amount: i64,
-                            state: &String,
-                            lichess_challenge_id: &String) -> Result<PgQueryResult, Error>{
-    sqlx::query( "INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id) VALUES ($1, $2, $3, $4, $5, $6)")
-        .bind(username)
-        .bind(ttype)
-        .bind(detail)
-        .bind(amount)
-        .bind(state)
-        .bind(lichess_challenge_id)
-        .execute(tx).await
+// a dry run rolls back instead, leaving the plan for the report. returns whether it committed
+async fn commit_unless_dry_run(tx: sqlx::Transaction<'_, Postgres>, dry_run: bool) -> Result<bool, Error> {
+    if dry_run {
+        tx.rollback().await?;
+        Ok(false)
+    } else {
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+// fetches the game from lichess. None while it's still going or lichess hasn't found it often enough
+// to give up on it
+async fn fetch_outcome(config: &Config, client: &Client, challenge: &Challenge) -> LightningChessResult<Option<Outcome>> {
+    let lichess_challenge_id = challenge.lichess_challenge_id.as_ref()
+        .ok_or_else(|| LightningChessError::InvariantViolation(format!("accepted challenge {} has no lichess challenge id", challenge.id)))?;
+    let url = format!("{}/game/export/{}", config.lichess.url, lichess_challenge_id);
+    LICHESS_THROTTLE.wait(config.lichess.request_interval()).await;
+    let request_timer = metrics::EXTERNAL_REQUEST_DURATION.with_label_values(&["lichess", "game_export"]).start_timer();
+    let resp = client
+        .get(url)
+        .header("Accept", "application/json")
+        .send().await;
+    request_timer.observe_duration();
+    metrics::record_request_result("lichess", "game_export", &resp);
+    let resp = resp.map_err(LightningChessError::lichess)?;
+
+    if resp.status().as_u16() == 429 {
+        warn!("lichess rate limit hit. holding every request back for a minute");
+        LICHESS_THROTTLE.back_off(LICHESS_RATE_LIMIT_BACKOFF);
+        return Ok(None);
+    }
+    if resp.status().as_u16() == 404 {
+        let missing_for = POLLING.record_miss(challenge.id);
+        warn!(?missing_for, "lichess game not found");
+        // if we keep getting 404, the game was never played
+        if missing_for >= config.settlement.missing_game_after() {
+            info!("game never played. returning sats");
+            return Ok(Some(Outcome::Unplayed));
+        }
+        return Ok(None);
+    }
+
+    debug!(status = %resp.status(), "lichess game export");
+
+    let resp = resp.error_for_status().map_err(LightningChessError::lichess)?;
+    let text = resp.text().await.map_err(LightningChessError::lichess)?;
+    let lichess_export_game_response: LichessExportGameResponse = serde_json::from_str(&text)?;
+    debug!(response = ?lichess_export_game_response, "parsed game export");
+
+    POLLING.record_game_status(challenge.id, &lichess_export_game_response.status);
+    let outcome = lichess_outcome(challenge, &lichess_export_game_response)?;
+    if outcome.is_none() {
+        debug!("challenge not over yet");
+    }
+    Ok(outcome)
 }
 
-async fn add_to_balance(tx: &mut sqlx::Transaction<'_, Postgres>, username: &String, amt: i64) -> Result<PgQueryResult, Error> {
-    sqlx::query( "UPDATE lightningchess_balance set balance=balance + $1 WHERE username=$2")
-        .bind(amt)
-        .bind(username)
-        .execute(tx).await
+#[instrument(skip_all, fields(challenge_id = challenge.id, lichess_challenge_id = ?challenge.lichess_challenge_id))]
+pub async fn settle_challenge(pool: &Pool<Postgres>,
+                              config: &Config,
+                              client: &Client,
+                              notifier: &Notifier,
+                              challenge: &Challenge,
+                              dry_run: bool) -> LightningChessResult<Option<SettlementPlan>> {
+    let _timer = metrics::SETTLEMENT_DURATION.with_label_values(&["settle"]).start_timer();
+    debug!("processing challenge");
+    let fee_basis_points = config.settlement.fee_basis_points;
+    // a challenge that can't be paid out is left for an operator without asking lichess about it
+    calculate_payouts(challenge, fee_basis_points)?;
+
+    let outcome = match fetch_outcome(config, client, challenge).await? {
+        Some(outcome) => outcome,
+        None => return Ok(None)
+    };
+
+    let game = format!("lichess game https://lichess.org/{}", challenge.lichess_challenge_id.as_deref().unwrap_or(""));
+    let (detail, label) = match &outcome {
+        Outcome::Win(_) => (game, "settled"),
+        // no winner so return money to both people
+        Outcome::Draw => (format!("{}. initial sats minus {}% fee", game, format_fee_percent(fee_basis_points)), "drawn"),
+        Outcome::Unplayed => ("sats returned for expired game".to_string(), "refunded")
+    };
+    let plan = plan_settlement(challenge, &outcome, fee_basis_points, &config.settlement.admin_account, &detail)?;
+
+    let mut tx = pool.begin().await?;
+    if lock_challenge(&mut tx, challenge.id, "ACCEPTED").await?.is_none() {
+        debug!("challenge locked or settled elsewhere. skipping");
+        return Ok(None);
+    }
+    apply_plan(&mut tx, notifier, challenge, &plan).await?;
+    debug!("update challenge succeeded");
+    if commit_unless_dry_run(tx, dry_run).await? {
+        info!(?outcome, "challenge {}", label);
+        metrics::CHALLENGES.with_label_values(&[label]).inc();
+        record_plan_metrics(&plan);
+    }
+    Ok(Some(plan))
 }
 
-async fn mark_challenge_completed(tx: &mut sqlx::Transaction<'_, Postgres>, challenge_id: i32) -> Result<Challenge, Error> {
-    sqlx::query_as::<_,Challenge>("UPDATE challenge SET status='COMPLETED' WHERE id=$1 RETURNING *")
-        .bind(challenge_id)
-        .fetch_one(tx).await
+// the checks a job runs and where each scan picks up on the next pass. a pass cut short by the
+// budget or shutdown stops after the last challenge it reached, so a backlog bigger than one pass is
+// still worked through in order instead of every pass going back to the oldest challenges. a scan
+// starts over from the oldest only once it has been through its last page
+#[derive(Debug)]
+pub struct Scans {
+    checks: Checks,
+    settle_after: i32,
+    // accepted challenges checked since the settle scan last started over
+    settle_checked: Vec<i32>,
+    expire_after: i32
 }
 
-async fn mark_challenge_expired(tx: &mut sqlx::Transaction<'_, Postgres>, challenge_id: i32) -> Result<Challenge, Error> {
-    sqlx::query_as::<_,Challenge>("UPDATE challenge SET status='EXPIRED' WHERE id=$1 RETURNING *")
-        .bind(challenge_id)
-        .fetch_one(tx).await
+impl Scans {
+    pub fn new(checks: Checks) -> Scans {
+        Scans { checks, settle_after: 0, settle_checked: Vec::new(), expire_after: 0 }
+    }
 }
 
-async fn check(pool: &Pool<Postgres>, expired_challenges: &mut HashMap<String, i32>) -> LightningChessResult<usize> {
-    let admin = env::var("ADMIN_ACCOUNT").unwrap();
-
-    // look up all the challenges in ACCEPTED status
-    let challenges = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE STATUS='ACCEPTED' ORDER BY created_on DESC LIMIT 1000")
-        .fetch_all(pool).await?;
-
-    let num_challenges = challenges.len();
-    println!("num_challenges: {}", num_challenges);
-
-    // check in lichess if there are any updates
-    for challenge in challenges.iter() {
-        println!("processing challenge {}", serde_json::to_string(challenge).unwrap());
-        let lichess_challenge_id = challenge.lichess_challenge_id.as_ref().unwrap();
-        let url = format!("https://lichess.org/game/export/{}", lichess_challenge_id);
-        let resp = Client::new()
-            .get(url)
-            .header("Accept", "application/json")
-            .send().await?;
-
-        let fee_per_person = calculate_fee_per_person(challenge);
-        let total_fee = fee_per_person * 2;
-
-        let mut tx = pool.begin().await?;
-
-        if resp.status().as_u16() == 404 {
-            println!("404 game found for {}", lichess_challenge_id);
-            let count = expired_challenges.entry(lichess_challenge_id.to_string()).or_insert(1);
-            println!("count {count}");
-            // if we get 404 for 30 cycles, mark as COMPLETED in draw
-            if count > &mut 30 {
-                println!("expired challenge {}. setting draw", lichess_challenge_id);
-                let expired_ttype = "expired".to_string();
-                let expired_detail = format!("sats returned for expired game");
-                let expired_amt = challenge.sats.unwrap();
-                let expired_state = "SETTLED".to_string();
-                println!("insert expired transaction 1");
-                insert_tx(&mut tx, &challenge.username, &expired_ttype, &expired_detail, expired_amt, &expired_state, lichess_challenge_id).await?;
-
-                println!("update expired balance 1");
-                add_to_balance(&mut tx, &challenge.username, expired_amt).await?;
-
-                println!("insert expired transaction 2");
-                insert_tx(&mut tx, &challenge.opp_username, &expired_ttype, &expired_detail, expired_amt, &expired_state, lichess_challenge_id).await?;
-
-                println!("update expired balance 2");
-                add_to_balance(&mut tx, &challenge.opp_username, expired_amt).await?;
-
-                mark_challenge_completed(&mut tx, challenge.id).await?;
-                println!("update challenge succeeded");
-
-                tx.commit().await?;
-                println!("committed");
+// walks the accepted challenges oldest first from the settle cursor, a page at a time, until the
+// backlog or the pass budget runs out. up to settlement.workers challenges on a page are checked at
+// once, with the lichess throttle spacing out their requests. a retryable error, like lichess or the
+// db being down, stops the pass once its page is done and the page is tried again next pass.
+// anything else is particular to the challenge, so it's skipped
+async fn check(pool: &Pool<Postgres>,
+               config: &Config,
+               client: &Client,
+               notifier: &Notifier,
+               shutdown: &Shutdown,
+               scans: &mut Scans,
+               dry_run: bool) -> LightningChessResult<Vec<SettlementPlan>> {
+    let deadline = Instant::now() + config.settlement.pass_budget();
+    let mut num_challenges = 0;
+    let mut settled = Vec::new();
+    loop {
+        let challenges = accepted_challenges(pool, scans.settle_after).await?;
+        debug!(after = scans.settle_after, num_challenges = challenges.len(), "checking page of accepted challenges");
+
+        // check in lichess if there are any updates. None when the challenge wasn't started
+        let settlements: Vec<_> = challenges.iter()
+            .map(|challenge| async move {
+                if shutdown.is_requested() || Instant::now() >= deadline {
+                    return (challenge.id, None);
+                }
+                let result = settle_challenge(pool, config, client, notifier, challenge, dry_run).await;
+                (challenge.id, Some(result.map_err(|e| e.for_challenge(challenge.id))))
+            })
+            .collect();
+        // buffered starts them in order, so the ones the deadline cut off are the end of the page
+        let results: Vec<_> = stream::iter(settlements)
+            .buffered(config.settlement.workers)
+            .collect().await;
+
+        let mut first_error = None;
+        let mut checked = Vec::new();
+        for (challenge_id, result) in results {
+            match result {
+                None => (),
+                Some(Ok(plan)) => {
+                    checked.push(challenge_id);
+                    POLLING.record_success(challenge_id);
+                    settled.extend(plan);
+                }
+                Some(Err(e)) => {
+                    checked.push(challenge_id);
+                    POLLING.record_error(challenge_id, e.to_string());
+                    metrics::CHALLENGES.with_label_values(&["failed"]).inc();
+                    if e.is_retryable() {
+                        first_error.get_or_insert(e);
+                    } else {
+                        error!(error = %e, "skipping challenge");
+                    }
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        num_challenges += checked.len();
+        let unfinished = checked.len() < challenges.len();
+        if let Some(last) = checked.last() {
+            scans.settle_after = *last;
+        }
+        scans.settle_checked.extend(checked);
+        if unfinished {
+            if shutdown.is_requested() {
+                info!(after = scans.settle_after, "shutdown requested. leaving remaining accepted challenges for the next run");
             } else {
-                *count += 1;
+                warn!(checked = num_challenges, after = scans.settle_after, "pass budget used up. leaving remaining accepted challenges for the next run");
             }
-
-            continue;
+            break;
         }
 
-        println!("Status: {}", resp.status());
-        println!("Headers:\n{:#?}", resp.headers());
-
-        let text = resp.text().await?;
-        println!("raw text {}", text);
+        if challenges.len() as i64 != PAGE_SIZE {
+            // only a full cycle knows which challenges stopped waiting on lichess. a partial one
+            // would drop the 404 counts of the ones it didn't reach
+            POLLING.retain(&scans.settle_checked);
+            scans.settle_checked.clear();
+            scans.settle_after = 0;
+            break;
+        }
+    }
 
-        let mut lichess_export_game_response: LichessExportGameResponse = serde_json::from_str(&text)?;
-        println!("to domain type {lichess_export_game_response:?}");
+    info!(num_challenges, num_settled = settled.len(), "checked accepted challenges");
+    Ok(settled)
+}
 
-        let challenge_lichess_result = lichess_export_game_response.status;
-        if challenge_lichess_result == "created" || challenge_lichess_result == "started" {
-            println!("challenge not over yet {}", lichess_challenge_id);
-            continue;
+// challenges in WAITING FOR ACCEPTANCE status that are past their expire_after, oldest first from
+// the expire cursor. like check, only a retryable error stops the pass
+async fn check_expired(pool: &Pool<Postgres>, config: &Config, notifier: &Notifier, shutdown: &Shutdown, scans: &mut Scans, dry_run: bool) -> LightningChessResult<Vec<SettlementPlan>> {
+    let deadline = Instant::now() + config.settlement.pass_budget();
+    let mut num_challenges = 0;
+    let mut expired = Vec::new();
+    'pages: loop {
+        let challenges = expired_challenges(pool, config.settlement.default_expire_after_seconds, scans.expire_after).await?;
+        debug!(after = scans.expire_after, num_challenges = challenges.len(), "checking page of expired challenges");
+
+        for challenge in challenges.iter() {
+            if shutdown.is_requested() {
+                info!(after = scans.expire_after, "shutdown requested. leaving remaining expired challenges for the next run");
+                break 'pages;
+            }
+            if Instant::now() >= deadline {
+                warn!(checked = num_challenges, after = scans.expire_after, "pass budget used up. leaving remaining expired challenges for the next run");
+                break 'pages;
+            }
+            num_challenges += 1;
+            match expire_challenge(pool, notifier, challenge, dry_run).await {
+                Ok(plan) => expired.extend(plan),
+                Err(e) => {
+                    metrics::CHALLENGES.with_label_values(&["failed"]).inc();
+                    let e = e.for_challenge(challenge.id);
+                    if e.is_retryable() {
+                        return Err(e);
+                    }
+                    error!(error = %e, "skipping challenge");
+                }
+            }
+            scans.expire_after = challenge.id;
         }
 
-        // pay admin
-        let admin_ttype = "fee".to_string();
-        let admin_detail = format!("fee from challenge {}", challenge.id);
-        let admin_state = "SETTLED".to_string();
-        println!("insert admin transaction");
-        insert_tx(&mut tx, &admin, &admin_ttype, &admin_detail, total_fee, &admin_state, lichess_challenge_id).await?;
-
-        println!("update admin balance");
-        add_to_balance(&mut tx, &admin, total_fee).await?;
-
-        let winner = lichess_export_game_response.winner.get_or_insert("".to_string());
-        if winner == "black" || winner == "white" {
-            // pay money to winner
-            let winner_username = get_winner_username(&challenge, winner);
-            let winner_ttype = "winnings".to_string();
-            let winner_detail = format!("lichess game https://lichess.org/{}", lichess_challenge_id);
-            let winning_amt = (&challenge.sats.unwrap() * 2) - total_fee;
-            let winner_state = "SETTLED".to_string();
-            println!("insert winner transaction");
-            insert_tx(&mut tx, &winner_username, &winner_ttype, &winner_detail, winning_amt, &winner_state, lichess_challenge_id).await?;
-
-            println!("update winner balance");
-            add_to_balance(&mut tx, &winner_username, winning_amt).await?;
-        } else {
-            // no winner so return money to both people
-            let draw_ttype = "draw".to_string();
-            let draw_detail = format!("lichess game https://lichess.org/{}. initial sats minus 2% fee", lichess_challenge_id);
-            let draw_amt = &challenge.sats.unwrap() - fee_per_person;
-            let draw_state = "SETTLED".to_string();
-            println!("insert draw transaction 1");
-            insert_tx(&mut tx, &challenge.username, &draw_ttype, &draw_detail, draw_amt, &draw_state, lichess_challenge_id).await?;
-
-            println!("update draw balance 1");
-            add_to_balance(&mut tx, &challenge.username, draw_amt).await?;
-
-            println!("insert draw transaction 2");
-            insert_tx(&mut tx, &challenge.opp_username, &draw_ttype, &draw_detail, draw_amt, &draw_state, lichess_challenge_id).await?;
-
-            println!("update draw balance 2");
-            add_to_balance(&mut tx, &challenge.opp_username, draw_amt).await?;
+        if challenges.len() as i64 != PAGE_SIZE {
+            scans.expire_after = 0;
+            break;
         }
+    }
 
-        // mark challenge as completed
-        mark_challenge_completed(&mut tx, challenge.id).await?;
-        println!("update challenge succeeded");
+    info!(num_challenges, num_expired = expired.len(), "checked expired challenges");
+    Ok(expired)
+}
 
-        tx.commit().await?;
-        println!("committed");
+#[instrument(skip_all, fields(challenge_id = challenge.id))]
+async fn expire_challenge(pool: &Pool<Postgres>, notifier: &Notifier, challenge: &Challenge, dry_run: bool) -> LightningChessResult<Option<SettlementPlan>> {
+    let _timer = metrics::SETTLEMENT_DURATION.with_label_values(&["expire"]).start_timer();
+    let mut tx = pool.begin().await?;
+    if lock_challenge(&mut tx, challenge.id, "WAITING FOR ACCEPTANCE").await?.is_none() {
+        debug!("challenge locked or accepted elsewhere. skipping");
+        return Ok(None);
     }
+    debug!("setting challenge to expired");
+    let ledger = get_challenge_ledger(&mut tx, challenge).await?;
+    let expired_detail = format!("sats returned for expired challenge {}", challenge.id);
+    let plan = plan_refund(challenge, &Refund::Expiry, &ledger, &expired_detail)?;
+
+    apply_plan(&mut tx, notifier, challenge, &plan).await?;
+    debug!("update challenge succeeded");
+
+    if commit_unless_dry_run(tx, dry_run).await? {
+        info!(num_refunds = plan.ledger.len(), "challenge expired");
+        metrics::CHALLENGES.with_label_values(&["expired"]).inc();
+        record_plan_metrics(&plan);
+    }
+    Ok(Some(plan))
+}
 
-    Ok(num_challenges)
+// counts and oldest age of the challenges the jobs are waiting on
+async fn record_challenge_gauges(pool: &Pool<Postgres>) -> LightningChessResult<()> {
+    let rows = pending_challenges(pool).await?;
+
+    for status in ["ACCEPTED", "WAITING FOR ACCEPTANCE"] {
+        let (count, oldest_age) = rows.iter()
+            .find(|row| row.status == status)
+            .map(|row| (row.count, row.oldest_age))
+            .unwrap_or((0, 0));
+        metrics::PENDING_CHALLENGES.with_label_values(&[status]).set(count);
+        if status == "ACCEPTED" {
+            metrics::OLDEST_ACCEPTED_CHALLENGE_AGE.set(oldest_age);
+        }
+    }
+    Ok(())
 }
 
-async fn check_expired(pool: &Pool<Postgres>) -> LightningChessResult<usize> {
-    // look up all the challenges in ACCEPTED status
-    let challenges = sqlx::query_as::<_,Challenge>( "SELECT * FROM challenge WHERE STATUS='WAITING FOR ACCEPTANCE' ORDER BY created_on DESC LIMIT 1000")
-        .fetch_all(pool).await?;
-
-    let num_challenges = challenges.len();
-    println!("num_challenges: {}", num_challenges);
-
-    // unix time
-    let current_seconds = Utc::now().timestamp();
-    for challenge in challenges.iter() {
-        println!("processing challenge {}", serde_json::to_string(challenge).unwrap());
-        let mut tx = pool.begin().await?;
-        let created_on: NaiveDateTime = challenge.created_on.unwrap();
-        let challenge_seconds = created_on.timestamp();
-        let diff_seconds = &current_seconds - challenge_seconds;
-        let challenge_id= &challenge.id;
-        println!("challenge: {challenge_id} diff_seconds: {diff_seconds}");
-        // 30 min to seconds = 1800
-        if diff_seconds > 1_800 {
-            println!("setting challenge to expired");
-            let expired_ttype = "expired".to_string();
-            let expired_detail = format!("sats returned for expired game");
-            let expired_amt = challenge.sats.unwrap();
-            let expired_state = "SETTLED".to_string();
-            let lichess_id = "none. expired".to_string();
-            println!("insert expired transaction 1");
-            insert_tx(&mut tx, &challenge.username, &expired_ttype, &expired_detail, expired_amt, &expired_state, &lichess_id).await?;
-
-            println!("update expired balance 1");
-            add_to_balance(&mut tx, &challenge.username, expired_amt).await?;
-
-            println!("insert expired transaction 2");
-            insert_tx(&mut tx, &challenge.opp_username, &expired_ttype, &expired_detail, expired_amt, &expired_state, &lichess_id).await?;
-
-            println!("update expired balance 2");
-            add_to_balance(&mut tx, &challenge.opp_username, expired_amt).await?;
-
-            mark_challenge_expired(&mut tx, challenge.id).await?;
-            println!("update challenge succeeded");
-
-            tx.commit().await?;
-            println!("committed");
+// waits until either a wakeup notification arrives, the poll interval passes or shutdown is requested.
+// the next pass covers every notification that's already queued, so they're drained instead of each
+// starting another pass
+async fn wait_for_wakeup(listener: &mut PgListener, poll_interval: Duration, shutdown: &Shutdown) {
+    debug!(?poll_interval, "sleeping until notified");
+    tokio::select! {
+        _ = sleep(poll_interval) => debug!("poll interval elapsed"),
+        _ = shutdown.requested() => debug!("woken by shutdown"),
+        notification = listener.recv() => match notification {
+            Ok(notification) => info!(channel = notification.channel(), payload = notification.payload(), "woken by notification"),
+            Err(e) => {
+                // the listener reconnects on the next recv. fall back to the timer so we don't spin
+                error!(error = %e, "error receiving notification");
+                tokio::select! {
+                    _ = sleep(poll_interval) => (),
+                    _ = shutdown.requested() => ()
+                }
+            }
         }
     }
-    Ok(num_challenges)
+    let mut coalesced = 0;
+    while let Some(Ok(Some(_))) = listener.try_recv().now_or_never() {
+        coalesced += 1;
+    }
+    if coalesced > 0 {
+        debug!(coalesced, "coalesced queued notifications into the next pass");
+    }
 }
 
-pub async fn db_checks() -> () {
-    println!("Starting db checks!");
-    let db_url = env::var("DB_URL").unwrap();
+// which checks a pass runs. settling and expiring are separate jobs so they can be scaled separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checks {
+    pub settle: bool,
+    pub expire: bool
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(1)
-        .connect(&db_url)
-        .await.unwrap();
+pub const SETTLE: Checks = Checks { settle: true, expire: false };
+pub const EXPIRE: Checks = Checks { settle: false, expire: true };
 
-    let mut loop_count = 1;
-    let mut expired_challenges: HashMap<String, i32> = HashMap::new();
-    loop {
-        println!("starting db checks loop {}", loop_count);
-        // checks lichess to see if the game has finished
-        let _check_result = check(&pool, &mut expired_challenges).await;
+fn clients(config: &Config) -> Result<(Client, Notifier), JobError> {
+    let client = Client::builder()
+        .timeout(config.lichess.request_timeout())
+        .build()
+        .map_err(|e| JobError::Fatal(format!("can't build lichess client: {}", e)))?;
+
+    let notifier = Notifier::from_config(config)?;
+    Ok((client, notifier))
+}
 
-        // checks challenges to see if any have expired in 30min
-        let _check_expired_result = check_expired(&pool).await;
+// one pass of the enabled checks. returns what they changed, or None if any of them failed
+async fn run_checks(pool: &Pool<Postgres>,
+                    config: &Config,
+                    client: &Client,
+                    notifier: &Notifier,
+                    shutdown: &Shutdown,
+                    scans: &mut Scans,
+                    dry_run: bool) -> Option<Vec<SettlementPlan>> {
+    let mut plans = Vec::new();
+
+    // checks lichess to see if the game has finished
+    let check_ok = !scans.checks.settle || match check(pool, config, client, notifier, shutdown, scans, dry_run).await {
+        Ok(settled) => {
+            plans.extend(settled);
+            true
+        }
+        Err(e) => {
+            error!(error = %e, retryable = e.is_retryable(), "error checking accepted challenges");
+            false
+        }
+    };
 
-        // makes sure that streaming didn't miss any invoices
-        //let _check_invoices = reconcile(&pool).await;
+    // checks challenges to see if any have passed their expire_after without being accepted
+    let check_expired_ok = !scans.checks.expire || match check_expired(pool, config, notifier, shutdown, scans, dry_run).await {
+        Ok(expired) => {
+            plans.extend(expired);
+            true
+        }
+        Err(e) => {
+            error!(error = %e, retryable = e.is_retryable(), "error checking expired challenges");
+            false
+        }
+    };
+
+    if let Err(e) = record_challenge_gauges(pool).await {
+        error!(error = %e, "error recording challenge gauges");
+    }
+    (check_ok && check_expired_ok).then_some(plans)
+}
 
-        let duration = Duration::from_secs(60);
+// the long running settle or expire job. job is the name its leader lock, pause switch and readiness go by
+pub async fn db_checks(job: &'static str, config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown, checks: Checks) -> JobResult {
+    info!(settle = checks.settle, expire = checks.expire, default_expire_after = config.settlement.default_expire_after_seconds, "starting db checks");
+    let (client, notifier) = clients(&config)?;
+
+    // holds one of the pool's connections for as long as the job runs
+    let mut listener = PgListener::connect_with(&pool).await?;
+    listener.listen_all(WAKEUP_CHANNELS).await?;
+
+    let mut scans = Scans::new(checks);
+    let mut loop_count = 1;
+    loop {
+        CONTROL.wait_while_paused(job, &shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        async {
+            if run_checks(&pool, &config, &client, &notifier, &shutdown, &mut scans, false).await.is_some() {
+                health::record_db_checks_success(job);
+            }
+        }.instrument(info_span!("db_checks_loop", loop_count)).await;
 
-        println!("sleeping for {duration:?}");
-        sleep(duration).await;
-        loop_count = loop_count + 1;
+        wait_for_wakeup(&mut listener, config.settlement.poll_interval(), &shutdown).await;
+        loop_count += 1;
     }
+    info!("db checks stopped");
+    Ok(())
+}
+
+// a single pass for cron and debugging. a lichess 404 is timed from the first one this process
+// saw, so games that were never played are left for the long running job to refund.
+// a dry run rolls back every challenge's transaction and only returns what it would have changed
+pub async fn db_checks_once(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown, checks: Checks, dry_run: bool) -> Result<Vec<SettlementPlan>, JobError> {
+    let (client, notifier) = clients(&config)?;
+    run_checks(&pool, &config, &client, &notifier, &shutdown, &mut Scans::new(checks), dry_run).await
+        .ok_or_else(|| JobError::Failed("db checks pass failed".to_string()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db;
+    use crate::test_support::{lichess_stub, test_db, TestDb};
 
-    fn get_challenge() -> Challenge {
-        Challenge { id: 1,
-            username: "user1".to_string(),
-            time_limit: None,
-            opponent_time_limit: None,
-            increment: None,
-            color: Some("white".to_string()),
-            sats: Some(100),
-            opp_username: "user2".to_string(),
-            status: None,
-            lichess_challenge_id: None,
-            created_on: None,
-            expire_after: None
+
+    async fn run_pass(config: Config, checks: Checks) {
+        let (_trigger, shutdown) = Shutdown::new();
+        let pool = db::connect(&config.db).await.unwrap();
+        db_checks_once(Arc::new(config), pool, shutdown, checks, false).await.unwrap();
+    }
+
+    async fn with_balances(db: &TestDb) {
+        for username in ["alice", "bob", "admin"] {
+            db.set_balance(username, 0).await;
         }
     }
 
-    #[test]
-    fn winner_creator_as_white() {
-        let challenge = get_challenge();
-
-        // creator won as white
-        assert_eq!(get_winner_username(&challenge, "white"), "user1");
-        // creator lost as white
-        assert_eq!(get_winner_username(&challenge, "black"), "user2");
-    }
-
-    #[test]
-    fn winner_creator_as_black() {
-        let mut challenge = get_challenge();
-        challenge.color = Some("black".to_string());
-
-        // creator lost as black
-        assert_eq!(get_winner_username(&challenge, "white"), "user2");
-        // creator won as black
-        assert_eq!(get_winner_username(&challenge, "black"), "user1");
-    }
-
-    #[test]
-    fn calculate_fee_per_person_test() {
-        let mut challenge = get_challenge();
-        assert_eq!(calculate_fee_per_person(&challenge), 2);
-        challenge.sats = Some(101);
-        assert_eq!(calculate_fee_per_person(&challenge), 2);
-        challenge.sats = Some(110);
-        assert_eq!(calculate_fee_per_person(&challenge), 2);
-        challenge.sats = Some(149);
-        assert_eq!(calculate_fee_per_person(&challenge), 2);
-        challenge.sats = Some(150);
-        assert_eq!(calculate_fee_per_person(&challenge), 3);
-        challenge.sats = Some(199);
-        assert_eq!(calculate_fee_per_person(&challenge), 3);
-        challenge.sats = Some(200);
-        assert_eq!(calculate_fee_per_person(&challenge), 4);
-    }
-}
\ No newline at end of file
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn settles_a_win() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let challenge_id = db.challenge("ACCEPTED", "black", "won", 0).await;
+        let lichess_url = lichess_stub(&[("won", "mate", Some("black"))]);
+
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "COMPLETED");
+        assert_eq!(db.ledger(challenge_id).await[2..], [
+            ("admin".to_string(), "fee".to_string(), 4),
+            ("alice".to_string(), "winnings".to_string(), 196)
+        ]);
+        assert_eq!((db.balance("alice").await, db.balance("bob").await, db.balance("admin").await), (196, 0, 4));
+
+        // settled challenges aren't picked up again
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.ledger(challenge_id).await.len(), 4);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn a_settle_pass_cut_short_carries_on_where_it_stopped() {
+        let db = test_db().await;
+        let mut challenge_ids = Vec::new();
+        for game in ["first", "second", "third"] {
+            challenge_ids.push(db.challenge("ACCEPTED", "white", game, 0).await);
+        }
+        let lichess_url = lichess_stub(&[("first", "started", None), ("second", "started", None), ("third", "started", None)]);
+        // the second request waits out the one second budget, so the third challenge isn't reached
+        let mut config = db.config(&lichess_url, "");
+        config.lichess.max_requests_per_second = 1;
+        config.settlement.workers = 1;
+        config.settlement.pass_budget_seconds = 1;
+        let (client, notifier) = clients(&config).unwrap();
+        let (_trigger, shutdown) = Shutdown::new();
+        let mut scans = Scans::new(SETTLE);
+
+        check(&db.pool, &config, &client, &notifier, &shutdown, &mut scans, false).await.unwrap();
+        assert_eq!(scans.settle_after, challenge_ids[1]);
+        assert!(POLLING.get(challenge_ids[2]).is_none());
+
+        // the next pass starts after the second challenge, and a finished cycle starts over
+        check(&db.pool, &config, &client, &notifier, &shutdown, &mut scans, false).await.unwrap();
+        assert_eq!(POLLING.get(challenge_ids[2]).and_then(|state| state.last_game_status), Some("started".to_string()));
+        assert_eq!((scans.settle_after, scans.settle_checked.len()), (0, 0));
+        for challenge_id in challenge_ids {
+            assert!(POLLING.get(challenge_id).is_some());
+        }
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn credits_players_without_a_balance_row() {
+        let db = test_db().await;
+        let challenge_id = db.challenge("ACCEPTED", "white", "won", 0).await;
+        let lichess_url = lichess_stub(&[("won", "mate", Some("white"))]);
+
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "COMPLETED");
+        assert_eq!((db.balance("alice").await, db.balance("admin").await), (196, 4));
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn settles_a_draw_and_waits_for_unfinished_games() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let drawn = db.challenge("ACCEPTED", "white", "drawn", 0).await;
+        let playing = db.challenge("ACCEPTED", "white", "playing", 0).await;
+        let lichess_url = lichess_stub(&[("drawn", "stalemate", None), ("playing", "started", None)]);
+
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(drawn).await, "COMPLETED");
+        assert_eq!((db.balance("alice").await, db.balance("bob").await, db.balance("admin").await), (98, 98, 4));
+        assert_eq!(db.status(playing).await, "ACCEPTED");
+        assert_eq!(db.ledger(playing).await.len(), 2);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn refunds_games_lichess_never_found() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let challenge_id = db.challenge("ACCEPTED", "white", "missing", 0).await;
+        let lichess_url = lichess_stub(&[]);
+        let mut config = db.config(&lichess_url, "");
+        // refund on the first 404 so the test doesn't wait between passes
+        config.settlement.missing_game_after_seconds = 0;
+
+        run_pass(config, SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "COMPLETED");
+        assert_eq!(db.ledger(challenge_id).await[2..], [
+            ("alice".to_string(), "expired".to_string(), 100),
+            ("bob".to_string(), "expired".to_string(), 100)
+        ]);
+        assert_eq!((db.balance("alice").await, db.balance("bob").await, db.balance("admin").await), (100, 100, 0));
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn expires_challenges_never_accepted() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        // expire_after is 60 seconds
+        let expired = db.challenge("WAITING FOR ACCEPTANCE", "white", "", 120).await;
+        let waiting = db.challenge("WAITING FOR ACCEPTANCE", "white", "", 0).await;
+
+        run_pass(db.config("", ""), EXPIRE).await;
+        assert_eq!(db.status(expired).await, "EXPIRED");
+        assert_eq!(db.ledger(expired).await, [
+            ("alice".to_string(), "challenge".to_string(), -100),
+            ("alice".to_string(), "expired".to_string(), 100)
+        ]);
+        assert_eq!(db.balance("alice").await, 100);
+        assert_eq!(db.status(waiting).await, "WAITING FOR ACCEPTANCE");
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn refunds_stakes_the_web_app_linked_by_lichess_id() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let unlinked = db.challenge("WAITING FOR ACCEPTANCE", "white", "unlinked", 120).await;
+        sqlx::query("UPDATE lightningchess_transaction SET challenge_id=NULL WHERE challenge_id=$1")
+            .bind(unlinked)
+            .execute(&db.pool).await.unwrap();
+
+        run_pass(db.config("", ""), EXPIRE).await;
+        assert_eq!(db.status(unlinked).await, "EXPIRED");
+        assert_eq!(db.ledger(unlinked).await, [("alice".to_string(), "expired".to_string(), 100)]);
+        assert_eq!(db.balance("alice").await, 100);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn records_challenges_that_cant_be_paid_out() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let challenge_id = db.challenge("ACCEPTED", "white", "nosats", 0).await;
+        sqlx::query("UPDATE challenge SET sats=NULL WHERE id=$1")
+            .bind(challenge_id)
+            .execute(&db.pool).await.unwrap();
+        let lichess_url = lichess_stub(&[("nosats", "mate", Some("white"))]);
+
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "ACCEPTED");
+        let last_error = POLLING.get(challenge_id).and_then(|state| state.last_error).unwrap();
+        assert!(last_error.contains(&format!("challenge {} has no sats", challenge_id)), "{}", last_error);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn leaves_challenges_without_a_stake_in_the_ledger() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        // a stake that isn't in the ledger at all, and one that can't be matched without a lichess id
+        let missing = db.challenge("WAITING FOR ACCEPTANCE", "white", "missing", 120).await;
+        let unmatched = db.challenge("WAITING FOR ACCEPTANCE", "white", "unmatched", 120).await;
+        sqlx::query("DELETE FROM lightningchess_transaction WHERE challenge_id=$1")
+            .bind(missing)
+            .execute(&db.pool).await.unwrap();
+        sqlx::query("UPDATE lightningchess_transaction SET challenge_id=NULL WHERE challenge_id=$1")
+            .bind(unmatched)
+            .execute(&db.pool).await.unwrap();
+        sqlx::query("UPDATE challenge SET lichess_challenge_id=NULL WHERE id=$1")
+            .bind(unmatched)
+            .execute(&db.pool).await.unwrap();
+
+        run_pass(db.config("", ""), EXPIRE).await;
+        for challenge_id in [missing, unmatched] {
+            assert_eq!(db.status(challenge_id).await, "WAITING FOR ACCEPTANCE");
+            assert_eq!(db.ledger(challenge_id).await, []);
+        }
+        assert_eq!(db.balance("alice").await, 0);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn coalesces_queued_wakeups() {
+        let db = test_db().await;
+        let (_trigger, shutdown) = Shutdown::new();
+        let mut listener = PgListener::connect_with(&db.pool).await.unwrap();
+        listener.listen_all(WAKEUP_CHANNELS).await.unwrap();
+        for challenge_id in 1..=3 {
+            sqlx::query("SELECT pg_notify('challenge_accepted', $1)")
+                .bind(challenge_id.to_string())
+                .execute(&db.pool).await.unwrap();
+        }
+        sleep(Duration::from_millis(200)).await;
+
+        wait_for_wakeup(&mut listener, Duration::from_secs(60), &shutdown).await;
+        assert!(listener.try_recv().now_or_never().is_none());
+        drop(listener);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn skips_challenges_locked_elsewhere() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let challenge_id = db.challenge("ACCEPTED", "white", "won", 0).await;
+        let lichess_url = lichess_stub(&[("won", "mate", Some("white"))]);
+
+        // another replica settling the same challenge
+        let mut other = db.pool.begin().await.unwrap();
+        sqlx::query("SELECT id FROM challenge WHERE id=$1 FOR UPDATE")
+            .bind(challenge_id)
+            .execute(&mut other).await.unwrap();
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "ACCEPTED");
+        other.rollback().await.unwrap();
+
+        run_pass(db.config(&lichess_url, ""), SETTLE).await;
+        assert_eq!(db.status(challenge_id).await, "COMPLETED");
+        assert_eq!(db.balance("alice").await, 196);
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn pages_through_the_whole_backlog_within_the_budget() {
+        let db = test_db().await;
+        with_balances(&db).await;
+        let mut challenge_ids = Vec::new();
+        for _ in 0..PAGE_SIZE + 2 {
+            challenge_ids.push(db.challenge("WAITING FOR ACCEPTANCE", "white", "", 120).await);
+        }
+
+        // an exhausted budget leaves everything for the next pass
+        let mut config = db.config("", "");
+        config.settlement.pass_budget_seconds = 0;
+        run_pass(config, EXPIRE).await;
+        assert_eq!(db.status(challenge_ids[0]).await, "WAITING FOR ACCEPTANCE");
+
+        run_pass(db.config("", ""), EXPIRE).await;
+        for challenge_id in challenge_ids {
+            assert_eq!(db.status(challenge_id).await, "EXPIRED");
+        }
+        assert_eq!(db.balance("alice").await, 100 * (PAGE_SIZE + 2));
+        db.drop().await;
+    }
+}
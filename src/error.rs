@@ -0,0 +1,203 @@
+use std::{error, fmt};
+use crate::models::{AdminError, SettlementError};
+
+pub type LightningChessResult<T> = Result<T, LightningChessError>;
+
+// everything the jobs can fail with. is_retryable tells a flaky network or database apart from
+// a bad row or bad config, which fail the same way however often they're retried
+#[derive(Debug)]
+pub enum LightningChessError {
+    Db(sqlx::Error),
+    // status is None when no response came back
+    Lichess { status: Option<u16>, message: String },
+    Lnd { status: Option<u16>, message: String },
+    // the webhook and email sinks. permanent when the receiving end refused the message
+    Notification { channel: &'static str, permanent: bool, message: String },
+    Parse(String),
+    InvariantViolation(String),
+    Config(String),
+    // what the job was working on when the error happened
+    Challenge { challenge_id: i32, source: Box<LightningChessError> },
+    Invoice { payment_addr: String, source: Box<LightningChessError> }
+}
+
+impl LightningChessError {
+    pub fn lichess(e: reqwest::Error) -> Self {
+        match http_error(&e) {
+            Some(error) => error,
+            None => LightningChessError::Lichess { status: e.status().map(|status| status.as_u16()), message: e.to_string() }
+        }
+    }
+
+    pub fn lnd(e: reqwest::Error) -> Self {
+        match http_error(&e) {
+            Some(error) => error,
+            None => LightningChessError::Lnd { status: e.status().map(|status| status.as_u16()), message: e.to_string() }
+        }
+    }
+
+    pub fn webhook(e: reqwest::Error) -> Self {
+        match http_error(&e) {
+            Some(error) => error,
+            None => LightningChessError::Notification { channel: "webhook", permanent: !retryable_status(e.status().map(|status| status.as_u16())), message: e.to_string() }
+        }
+    }
+
+    pub fn email(e: lettre::transport::smtp::Error) -> Self {
+        LightningChessError::Notification { channel: "email", permanent: e.is_permanent(), message: e.to_string() }
+    }
+
+    pub fn for_challenge(self, challenge_id: i32) -> Self {
+        LightningChessError::Challenge { challenge_id, source: Box::new(self) }
+    }
+
+    pub fn for_invoice(self, payment_addr: &str) -> Self {
+        LightningChessError::Invoice { payment_addr: payment_addr.to_string(), source: Box::new(self) }
+    }
+
+    // whether the same work could succeed on a later attempt
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LightningChessError::Db(e) => retryable_db_error(e),
+            LightningChessError::Lichess { status, .. } | LightningChessError::Lnd { status, .. } => retryable_status(*status),
+            LightningChessError::Notification { permanent, .. } => !permanent,
+            LightningChessError::Parse(_) | LightningChessError::InvariantViolation(_) | LightningChessError::Config(_) => false,
+            LightningChessError::Challenge { source, .. } | LightningChessError::Invoice { source, .. } => source.is_retryable()
+        }
+    }
+}
+
+// a response that couldn't be read as json or a request that couldn't be built isn't about the service
+fn http_error(e: &reqwest::Error) -> Option<LightningChessError> {
+    if e.is_decode() {
+        Some(LightningChessError::Parse(e.to_string()))
+    } else if e.is_builder() {
+        Some(LightningChessError::Config(e.to_string()))
+    } else {
+        None
+    }
+}
+
+// timeouts, dropped connections, rate limits and server errors. other 4xx responses are our fault
+fn retryable_status(status: Option<u16>) -> bool {
+    match status {
+        None => true,
+        Some(status) => status == 429 || status >= 500
+    }
+}
+
+fn retryable_db_error(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| retryable_sqlstate(&code)),
+        _ => false
+    }
+}
+
+// connection exceptions, serialization failures, deadlocks, statement timeouts, the server
+// shutting down and running out of connections
+fn retryable_sqlstate(code: &str) -> bool {
+    code.starts_with("08") || ["40001", "40P01", "57014", "57P01", "57P03", "53300"].contains(&code)
+}
+
+impl fmt::Display for LightningChessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LightningChessError::Db(e) => write!(f, "database error: {}", e),
+            LightningChessError::Lichess { status: Some(status), message } => write!(f, "lichess returned {}: {}", status, message),
+            LightningChessError::Lichess { status: None, message } => write!(f, "lichess request failed: {}", message),
+            LightningChessError::Lnd { status: Some(status), message } => write!(f, "lnd returned {}: {}", status, message),
+            LightningChessError::Lnd { status: None, message } => write!(f, "lnd request failed: {}", message),
+            LightningChessError::Notification { channel, message, .. } => write!(f, "{} notification failed: {}", channel, message),
+            LightningChessError::Parse(message) => write!(f, "parse error: {}", message),
+            LightningChessError::InvariantViolation(message) => write!(f, "invariant violated: {}", message),
+            LightningChessError::Config(message) => write!(f, "config error: {}", message),
+            LightningChessError::Challenge { challenge_id, source } => write!(f, "challenge {}: {}", challenge_id, source),
+            LightningChessError::Invoice { payment_addr, source } => write!(f, "invoice {}: {}", payment_addr, source)
+        }
+    }
+}
+
+impl error::Error for LightningChessError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LightningChessError::Db(e) => Some(e),
+            LightningChessError::Challenge { source, .. } | LightningChessError::Invoice { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
+
+impl From<sqlx::Error> for LightningChessError {
+    fn from(e: sqlx::Error) -> Self {
+        LightningChessError::Db(e)
+    }
+}
+
+impl From<serde_json::Error> for LightningChessError {
+    fn from(e: serde_json::Error) -> Self {
+        LightningChessError::Parse(e.to_string())
+    }
+}
+
+impl From<SettlementError> for LightningChessError {
+    fn from(e: SettlementError) -> Self {
+        LightningChessError::InvariantViolation(e.to_string())
+    }
+}
+
+// an operator command the challenge's state doesn't allow
+impl From<AdminError> for LightningChessError {
+    fn from(e: AdminError) -> Self {
+        LightningChessError::InvariantViolation(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lichess(status: Option<u16>) -> LightningChessError {
+        LightningChessError::Lichess { status, message: "".to_string() }
+    }
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LightningChessError>();
+    }
+
+    #[test]
+    fn retries_outages_but_not_bad_data() {
+        assert!(lichess(None).is_retryable());
+        assert!(lichess(Some(429)).is_retryable());
+        assert!(lichess(Some(503)).is_retryable());
+        assert!(!lichess(Some(400)).is_retryable());
+        assert!(LightningChessError::Lnd { status: Some(502), message: "".to_string() }.is_retryable());
+        assert!(LightningChessError::Db(sqlx::Error::PoolTimedOut).is_retryable());
+        assert!(!LightningChessError::Db(sqlx::Error::RowNotFound).is_retryable());
+        assert!(!LightningChessError::from(SettlementError::Overflow { challenge_id: 1 }).is_retryable());
+        assert!(!LightningChessError::Parse("".to_string()).is_retryable());
+        assert!(!LightningChessError::Config("".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn retries_transient_sqlstates() {
+        for code in ["08006", "40001", "40P01", "57014"] {
+            assert!(retryable_sqlstate(code), "{}", code);
+        }
+        for code in ["23505", "42P01", "22003"] {
+            assert!(!retryable_sqlstate(code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn context_keeps_the_classification() {
+        let error = lichess(Some(502)).for_challenge(7);
+        assert!(error.is_retryable());
+        assert_eq!(error.to_string(), "challenge 7: lichess returned 502: ");
+        let error = LightningChessError::Parse("bad amount".to_string()).for_invoice("addr");
+        assert!(!error.is_retryable());
+        assert_eq!(error.to_string(), "invoice addr: parse error: bad amount");
+    }
+}
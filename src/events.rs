@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, FromRow, Pool, Postgres};
+use sqlx::types::Json;
+use tokio::time::sleep;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::config::Config;
+use crate::control::{CONTROL, RELAY_EVENTS};
+use crate::error::LightningChessResult;
+use crate::sats::Sats;
+use crate::shutdown::Shutdown;
+use crate::supervisor::JobResult;
+
+// domain events are written to lightningchess_event_outbox in the same db transaction as the change.
+// the relay publishes them with NOTIFY on this channel and marks them dispatched
+pub const EVENTS_CHANNEL: &str = "lightningchess_events";
+const BATCH_SIZE: i64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum DomainEvent {
+    // outcome is one of win, draw or unplayed
+    ChallengeSettled { challenge_id: i32, lichess_challenge_id: Option<String>, outcome: String, winner: Option<String> },
+    ChallengeExpired { challenge_id: i32 },
+    // cancelled by an operator
+    ChallengeVoided { challenge_id: i32, note: String },
+    // stakes returned by an operator
+    ChallengeRefunded { challenge_id: i32, note: String },
+    DepositSettled { transaction_id: i32, username: String, amount: Sats, payment_addr: Option<String> }
+}
+
+impl DomainEvent {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            DomainEvent::ChallengeSettled { .. } => "ChallengeSettled",
+            DomainEvent::ChallengeExpired { .. } => "ChallengeExpired",
+            DomainEvent::ChallengeVoided { .. } => "ChallengeVoided",
+            DomainEvent::ChallengeRefunded { .. } => "ChallengeRefunded",
+            DomainEvent::DepositSettled { .. } => "DepositSettled"
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct OutboxEvent {
+    id: i64,
+    event_type: String,
+    payload: Json<DomainEvent>
+}
+
+// the message sent with NOTIFY. the outbox id lets listeners de-duplicate
+#[derive(Serialize)]
+struct PublishedEvent<'a> {
+    id: i64,
+    #[serde(flatten)]
+    event: &'a DomainEvent
+}
+
+pub async fn record_event(tx: &mut sqlx::Transaction<'_, Postgres>, event: &DomainEvent) -> Result<(), Error> {
+    sqlx::query("INSERT INTO lightningchess_event_outbox (event_type, payload) VALUES ($1, $2)")
+        .bind(event.event_type())
+        .bind(Json(event))
+        .execute(tx).await?;
+    Ok(())
+}
+
+async fn relay_pending(pool: &Pool<Postgres>) -> LightningChessResult<usize> {
+    let mut tx = pool.begin().await?;
+    let events = sqlx::query_as::<_, OutboxEvent>("SELECT id, event_type, payload FROM lightningchess_event_outbox WHERE dispatched_on IS NULL ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED")
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut tx).await?;
+
+    let num_events = events.len();
+    for event in events.iter() {
+        debug!(event_id = event.id, event_type = %event.event_type, "publishing event");
+        let published = PublishedEvent { id: event.id, event: &event.payload };
+        // notifications are only delivered when the transaction commits, together with dispatched_on
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(EVENTS_CHANNEL)
+            .bind(serde_json::to_string(&published)?)
+            .execute(&mut tx).await?;
+
+        sqlx::query("UPDATE lightningchess_event_outbox SET dispatched_on=(now() AT TIME ZONE 'UTC') WHERE id=$1")
+            .bind(event.id)
+            .execute(&mut tx).await?;
+    }
+
+    tx.commit().await?;
+    Ok(num_events)
+}
+
+pub async fn relay_events(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    info!("starting event relay");
+
+    let mut loop_count = 1;
+    loop {
+        CONTROL.wait_while_paused(RELAY_EVENTS, &shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        async {
+            match relay_pending(&pool).await {
+                Ok(num_events) => debug!(num_events, "relayed events"),
+                Err(e) if e.is_retryable() => warn!(error = %e, "error relaying events. retrying next pass"),
+                Err(e) => error!(error = %e, "error relaying events")
+            }
+        }.instrument(info_span!("event_relay_loop", loop_count)).await;
+        loop_count += 1;
+
+        tokio::select! {
+            _ = sleep(config.events.poll_interval()) => (),
+            _ = shutdown.requested() => ()
+        }
+    }
+    info!("event relay stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn published_event_payload() {
+        let event = DomainEvent::ChallengeSettled {
+            challenge_id: 1,
+            lichess_challenge_id: Some("abc".to_string()),
+            outcome: "win".to_string(),
+            winner: Some("user1".to_string())
+        };
+        let published = PublishedEvent { id: 7, event: &event };
+        assert_eq!(serde_json::to_string(&published).unwrap(),
+                   r#"{"id":7,"type":"ChallengeSettled","challenge_id":1,"lichess_challenge_id":"abc","outcome":"win","winner":"user1"}"#);
+    }
+
+    #[test]
+    fn event_types() {
+        assert_eq!(DomainEvent::ChallengeExpired { challenge_id: 1 }.event_type(), "ChallengeExpired");
+        let deposit = DomainEvent::DepositSettled { transaction_id: 1, username: "user1".to_string(), amount: Sats::new(10), payment_addr: None };
+        assert_eq!(deposit.event_type(), "DepositSettled");
+    }
+}
@@ -0,0 +1,254 @@
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+use chrono::Utc;
+use serde::Serialize;
+use crate::control::{EXPIRE_CHALLENGES, SETTLE_CHALLENGES, SUBSCRIBE_INVOICES};
+
+// the subscription reconnects straight away, so a long disconnect means lnd is unreachable
+const LND_STALE_AFTER_SECONDS: i64 = 60;
+
+// one of the db checks jobs, settling or expiring challenges
+struct CheckState {
+    enabled: AtomicBool,
+    standby: AtomicBool,
+    last_success: AtomicI64
+}
+
+impl CheckState {
+    const fn new() -> CheckState {
+        CheckState { enabled: AtomicBool::new(true), standby: AtomicBool::new(false), last_success: AtomicI64::new(0) }
+    }
+
+    fn observe(&self) -> ObservedCheck {
+        ObservedCheck {
+            enabled: self.enabled.load(Ordering::Relaxed),
+            standby: self.standby.load(Ordering::Relaxed),
+            last_success: self.last_success.load(Ordering::Relaxed)
+        }
+    }
+}
+
+// written by the jobs, read by the health endpoints. times are unix seconds, 0 means never
+pub struct HealthState {
+    settle: CheckState,
+    expire: CheckState,
+    lnd_enabled: AtomicBool,
+    lnd_standby: AtomicBool,
+    lnd_connected: AtomicBool,
+    lnd_status_changed: AtomicI64
+}
+
+pub static HEALTH: LazyLock<HealthState> = LazyLock::new(|| HealthState {
+    settle: CheckState::new(),
+    expire: CheckState::new(),
+    lnd_enabled: AtomicBool::new(true),
+    lnd_standby: AtomicBool::new(false),
+    lnd_connected: AtomicBool::new(false),
+    lnd_status_changed: AtomicI64::new(Utc::now().timestamp())
+});
+
+fn check_state(job: &str) -> Option<&'static CheckState> {
+    match job {
+        SETTLE_CHALLENGES => Some(&HEALTH.settle),
+        EXPIRE_CHALLENGES => Some(&HEALTH.expire),
+        _ => None
+    }
+}
+
+// jobs this process doesn't run are reported but don't affect readiness
+pub fn set_enabled(settle: bool, expire: bool, lnd_subscription: bool) {
+    HEALTH.settle.enabled.store(settle, Ordering::Relaxed);
+    HEALTH.expire.enabled.store(expire, Ordering::Relaxed);
+    HEALTH.lnd_enabled.store(lnd_subscription, Ordering::Relaxed);
+}
+
+// a standby replica waits for another to give up the job's leader lock. like a disabled job it
+// doesn't affect readiness
+pub fn set_standby(job: &str, standby: bool) {
+    if let Some(check) = check_state(job) {
+        check.standby.store(standby, Ordering::Relaxed);
+    } else if job == SUBSCRIBE_INVOICES {
+        // the disconnect grace period starts over when a standby takes over
+        if HEALTH.lnd_standby.swap(standby, Ordering::Relaxed) && !standby {
+            HEALTH.lnd_status_changed.store(Utc::now().timestamp(), Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn record_db_checks_success(job: &str) {
+    if let Some(check) = check_state(job) {
+        check.last_success.store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+}
+
+pub fn record_lnd_subscription(connected: bool) {
+    // only a change of state restarts the clock
+    if HEALTH.lnd_connected.swap(connected, Ordering::Relaxed) != connected {
+        HEALTH.lnd_status_changed.store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct DbCheckStatus {
+    pub ok: bool,
+    pub enabled: bool,
+    pub standby: bool,
+    pub seconds_since_last_success: Option<i64>
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct LndStatus {
+    pub ok: bool,
+    pub enabled: bool,
+    pub standby: bool,
+    pub connected: bool,
+    pub seconds_disconnected: Option<i64>
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub db_pool: bool,
+    pub settle_challenges: DbCheckStatus,
+    pub expire_challenges: DbCheckStatus,
+    pub lnd_subscription: LndStatus
+}
+
+struct ObservedCheck {
+    enabled: bool,
+    standby: bool,
+    last_success: i64
+}
+
+// a snapshot of HealthState
+struct Observed {
+    settle: ObservedCheck,
+    expire: ObservedCheck,
+    lnd_enabled: bool,
+    lnd_standby: bool,
+    lnd_connected: bool,
+    lnd_status_changed: i64
+}
+
+// a db checks job counts as stuck once db_checks_stale_after has passed without a successful pass
+fn evaluate_check(now: i64, db_checks_stale_after: i64, observed: ObservedCheck) -> DbCheckStatus {
+    let seconds_since_last_success = if observed.last_success == 0 { None } else { Some(now - observed.last_success) };
+    DbCheckStatus {
+        ok: !observed.enabled || observed.standby
+            || matches!(seconds_since_last_success, Some(seconds) if seconds <= db_checks_stale_after),
+        enabled: observed.enabled,
+        standby: observed.standby,
+        seconds_since_last_success
+    }
+}
+
+fn evaluate(now: i64, db_pool: bool, db_checks_stale_after: i64, observed: Observed) -> HealthReport {
+    let settle_challenges = evaluate_check(now, db_checks_stale_after, observed.settle);
+    let expire_challenges = evaluate_check(now, db_checks_stale_after, observed.expire);
+
+    let seconds_disconnected = if observed.lnd_connected { None } else { Some(now - observed.lnd_status_changed) };
+    let lnd_subscription = LndStatus {
+        ok: !observed.lnd_enabled || observed.lnd_standby
+            || seconds_disconnected.is_none_or(|seconds| seconds <= LND_STALE_AFTER_SECONDS),
+        enabled: observed.lnd_enabled,
+        standby: observed.lnd_standby,
+        connected: observed.lnd_connected,
+        seconds_disconnected
+    };
+
+    HealthReport {
+        ready: db_pool && settle_challenges.ok && expire_challenges.ok && lnd_subscription.ok,
+        db_pool,
+        settle_challenges,
+        expire_challenges,
+        lnd_subscription
+    }
+}
+
+pub fn report(db_pool: bool, db_checks_stale_after: Duration) -> HealthReport {
+    let db_checks_stale_after = i64::try_from(db_checks_stale_after.as_secs()).unwrap_or(i64::MAX);
+    evaluate(Utc::now().timestamp(), db_pool, db_checks_stale_after, Observed {
+        settle: HEALTH.settle.observe(),
+        expire: HEALTH.expire.observe(),
+        lnd_enabled: HEALTH.lnd_enabled.load(Ordering::Relaxed),
+        lnd_standby: HEALTH.lnd_standby.load(Ordering::Relaxed),
+        lnd_connected: HEALTH.lnd_connected.load(Ordering::Relaxed),
+        lnd_status_changed: HEALTH.lnd_status_changed.load(Ordering::Relaxed)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the default settlement config's
+    const DB_CHECKS_STALE_AFTER_SECONDS: i64 = 480;
+
+    fn check(enabled: bool, standby: bool, last_success: i64) -> ObservedCheck {
+        ObservedCheck { enabled, standby, last_success }
+    }
+
+    fn observed(last_db_checks_success: i64, lnd_connected: bool, lnd_status_changed: i64) -> Observed {
+        Observed { settle: check(true, false, last_db_checks_success), expire: check(true, false, last_db_checks_success), lnd_enabled: true, lnd_standby: false, lnd_connected, lnd_status_changed }
+    }
+
+    #[test]
+    fn ready_when_everything_is_fresh() {
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(990, true, 100));
+        assert!(report.ready);
+        assert_eq!(report.settle_challenges, DbCheckStatus { ok: true, enabled: true, standby: false, seconds_since_last_success: Some(10) });
+        assert_eq!(report.lnd_subscription, LndStatus { ok: true, enabled: true, standby: false, connected: true, seconds_disconnected: None });
+    }
+
+    #[test]
+    fn not_ready_before_first_db_checks_pass() {
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(0, true, 100));
+        assert!(!report.ready);
+        assert_eq!(report.settle_challenges, DbCheckStatus { ok: false, enabled: true, standby: false, seconds_since_last_success: None });
+    }
+
+    #[test]
+    fn not_ready_when_db_checks_stale() {
+        assert!(evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(1_000 - DB_CHECKS_STALE_AFTER_SECONDS, true, 100)).ready);
+        assert!(!evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(1_000 - DB_CHECKS_STALE_AFTER_SECONDS - 1, true, 100)).ready);
+    }
+
+    #[test]
+    fn not_ready_without_db_pool() {
+        assert!(!evaluate(1_000, false, DB_CHECKS_STALE_AFTER_SECONDS, observed(990, true, 100)).ready);
+    }
+
+    #[test]
+    fn lnd_disconnect_grace_period() {
+        // a short reconnect is fine
+        assert!(evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(990, false, 1_000 - LND_STALE_AFTER_SECONDS)).ready);
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, observed(990, false, 1_000 - LND_STALE_AFTER_SECONDS - 1));
+        assert!(!report.ready);
+        assert_eq!(report.lnd_subscription, LndStatus { ok: false, enabled: true, standby: false, connected: false, seconds_disconnected: Some(LND_STALE_AFTER_SECONDS + 1) });
+    }
+
+    #[test]
+    fn disabled_jobs_dont_affect_readiness() {
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, Observed { settle: check(false, false, 0), expire: check(false, false, 0), lnd_enabled: false, lnd_standby: false, lnd_connected: false, lnd_status_changed: 0 });
+        assert!(report.ready);
+        assert_eq!(report.settle_challenges, DbCheckStatus { ok: true, enabled: false, standby: false, seconds_since_last_success: None });
+    }
+
+    #[test]
+    fn standby_jobs_dont_affect_readiness() {
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, Observed { settle: check(true, true, 0), expire: check(true, true, 0), lnd_enabled: true, lnd_standby: true, lnd_connected: false, lnd_status_changed: 0 });
+        assert!(report.ready);
+        assert_eq!(report.lnd_subscription, LndStatus { ok: true, enabled: true, standby: true, connected: false, seconds_disconnected: Some(1_000) });
+    }
+
+    #[test]
+    fn each_db_checks_job_counts_on_its_own() {
+        // a replica running only expire is ready while another replica leads settle
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, Observed { settle: check(false, false, 0), expire: check(true, false, 990), lnd_enabled: false, lnd_standby: false, lnd_connected: false, lnd_status_changed: 0 });
+        assert!(report.ready);
+        let report = evaluate(1_000, true, DB_CHECKS_STALE_AFTER_SECONDS, Observed { settle: check(true, false, 990), expire: check(true, false, 0), lnd_enabled: false, lnd_standby: false, lnd_connected: false, lnd_status_changed: 0 });
+        assert!(!report.ready);
+        assert_eq!(report.expire_challenges, DbCheckStatus { ok: false, enabled: true, standby: false, seconds_since_last_success: None });
+    }
+}
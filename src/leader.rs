@@ -0,0 +1,124 @@
+use std::future::Future;
+use std::sync::Arc;
+use sqlx::{Connection, Pool, Postgres};
+use sqlx::postgres::PgConnection;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info};
+use crate::config::Config;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
+use crate::{health, metrics};
+
+// leader election between replicas. a job that must only run once, like the lnd subscription or
+// settlement, is run by whichever replica holds its postgres advisory lock. the lock goes with the
+// session, so a standby takes over within RETRY_INTERVAL of the leader's connection dropping
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+// how often the leader checks its session is still there. a lost session means a standby may
+// already hold the lock
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+// first half of every advisory lock key, so they can't collide with locks taken elsewhere
+const LOCK_NAMESPACE: i32 = 0x4c43_4a42;
+
+async fn try_lock(conn: &mut PgConnection, job: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT pg_try_advisory_lock($1, hashtext($2))")
+        .bind(LOCK_NAMESPACE)
+        .bind(job)
+        .fetch_one(conn).await
+}
+
+// resolves with the error once the session stops answering
+async fn heartbeat(conn: &mut PgConnection) -> sqlx::Error {
+    loop {
+        sleep(HEARTBEAT_INTERVAL).await;
+        if let Err(e) = conn.ping().await {
+            return e;
+        }
+    }
+}
+
+fn set_leader(job: &'static str, leader: bool) {
+    health::set_standby(job, !leader);
+    metrics::JOB_LEADER.with_label_values(&[job]).set(leader as i64);
+}
+
+// waits for the job's lock, then runs it until it finishes, shutdown is requested or the lock is lost
+pub async fn lead<F, Fut>(job: &'static str, config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown, run: F) -> JobResult
+    where F: FnOnce(Arc<Config>, Pool<Postgres>, Shutdown) -> Fut,
+          Fut: Future<Output = JobResult> {
+    // the lock lives as long as the session, so the connection is kept out of the pool
+    let mut conn = pool.acquire().await?.detach();
+    set_leader(job, false);
+    while !try_lock(&mut conn, job).await? {
+        tokio::select! {
+            _ = sleep(RETRY_INTERVAL) => (),
+            _ = shutdown.requested() => return Ok(())
+        }
+    }
+    info!("took the leader lock");
+    set_leader(job, true);
+
+    let (trigger, job_shutdown) = Shutdown::new();
+    let running = run(config, pool, job_shutdown);
+    tokio::pin!(running);
+    let result = tokio::select! {
+        result = &mut running => result,
+        _ = shutdown.requested() => {
+            let _ = trigger.send(true);
+            running.await
+        }
+        e = heartbeat(&mut conn) => {
+            error!(error = %e, "lost the leader lock. stopping");
+            let _ = trigger.send(true);
+            let _ = running.await;
+            Err(JobError::Failed(format!("lost the leader lock: {}", e)))
+        }
+    };
+
+    set_leader(job, false);
+    // closing the session releases the lock
+    let _ = conn.close().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+    use crate::test_support::test_db;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn standby_takes_over_when_the_leader_stops() {
+        let db = test_db().await;
+        // test runs can share the database, so each takes its own lock
+        let job: &'static str = Box::leak(format!("leader_test_{}", std::process::id()).into_boxed_str());
+        let config = Arc::new(db.config("", ""));
+
+        let (leader_trigger, leader_shutdown) = Shutdown::new();
+        let (started, leading) = oneshot::channel();
+        let leader = tokio::spawn(lead(job, config.clone(), db.pool.clone(), leader_shutdown, |_, _, shutdown| async move {
+            let _ = started.send(());
+            shutdown.requested().await;
+            Ok(())
+        }));
+        leading.await.unwrap();
+
+        let (standby_trigger, standby_shutdown) = Shutdown::new();
+        let (started, standing_by) = oneshot::channel();
+        let standby = tokio::spawn(lead(job, config, db.pool.clone(), standby_shutdown, |_, _, _| async move {
+            let _ = started.send(());
+            Ok(())
+        }));
+        let mut standing_by = Box::pin(standing_by);
+        assert!(timeout(RETRY_INTERVAL * 2, &mut standing_by).await.is_err());
+
+        leader_trigger.send(true).unwrap();
+        leader.await.unwrap().unwrap();
+        timeout(RETRY_INTERVAL * 2, standing_by).await.unwrap().unwrap();
+        standby.await.unwrap().unwrap();
+        drop(standby_trigger);
+        db.drop().await;
+    }
+}
@@ -0,0 +1,17 @@
+use std::env;
+use tracing_subscriber::EnvFilter;
+
+// RUST_LOG sets the level filter. LOG_FORMAT=json switches to one json object per line.
+// logs go to stderr so dry run reports on stdout can be piped
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,sqlx=warn"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_writer(std::io::stderr);
+
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().flatten_event(true).with_current_span(true).init(),
+        _ => builder.init()
+    }
+}
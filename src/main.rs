@@ -1,18 +1,159 @@
 mod subscribe_lnd;
+mod admin;
+mod admin_api;
+mod cli;
+mod config;
+mod control;
+mod db;
 mod db_checks;
 mod reconcile_invoices;
 mod models;
+mod error;
+mod events;
+mod health;
+mod leader;
+mod logging;
+mod metrics;
+mod migrate;
+mod server;
+mod settlement;
+mod notifications;
+mod polling;
+mod report;
+mod repository;
+mod sats;
+mod shutdown;
+mod supervisor;
+mod throttle;
+#[cfg(test)]
+mod test_support;
 
+use std::future::Future;
+use std::process::exit;
+use std::sync::Arc;
+use clap::Parser;
+use sqlx::{Pool, Postgres};
+use tracing::{error, info};
+use crate::cli::{selected_jobs, Cli, Command, Job, ReportFormat};
+use crate::config::Config;
+use crate::control::{DELIVER_NOTIFICATIONS, EXPIRE_CHALLENGES, RECONCILE_INVOICES, RELAY_EVENTS, SETTLE_CHALLENGES, SUBSCRIBE_INVOICES};
 use crate::subscribe_lnd::subscribe_invoices;
-use crate::db_checks::db_checks;
+use crate::db_checks::{db_checks, db_checks_once, Checks, EXPIRE, SETTLE};
+use crate::notifications::deliver_notifications;
+use crate::events::relay_events;
+use crate::leader::lead;
+use crate::logging::init_logging;
+use crate::migrate::{check_migrated, migrate};
+use crate::reconcile_invoices::{reconcile_invoices, reconcile_once};
+use crate::server::serve_http;
+use crate::shutdown::{wait_for_signal, Shutdown};
+use crate::supervisor::{JobResult, Supervisor};
+
+// hands each run of a job its own reference to the config and the shared pool
+fn with_shared<F, Fut>(config: &Arc<Config>, pool: &Pool<Postgres>, run: F) -> impl Fn(Shutdown) -> Fut
+    where F: Fn(Arc<Config>, Pool<Postgres>, Shutdown) -> Fut,
+          Fut: Future<Output = JobResult> {
+    let config = config.clone();
+    let pool = pool.clone();
+    move |shutdown| run(config.clone(), pool.clone(), shutdown)
+}
+
+async fn run(config: Arc<Config>, pool: Pool<Postgres>, only: Vec<Job>) -> Result<(), String> {
+    let jobs = selected_jobs(&only);
+    info!(?jobs, "running jobs");
+    if config.db.migrate_on_startup {
+        migrate(&pool).await.map_err(|e| e.to_string())?;
+    } else {
+        check_migrated(&pool).await.map_err(|e| e.to_string())?;
+    }
+    health::set_enabled(jobs.contains(&Job::Settle), jobs.contains(&Job::Expire), jobs.contains(&Job::Invoices));
+
+    let mut supervisor = Supervisor::new()
+        .add("http", with_shared(&config, &pool, serve_http));
+    if jobs.contains(&Job::Invoices) {
+        supervisor = supervisor.add(SUBSCRIBE_INVOICES, with_shared(&config, &pool, |config, pool, shutdown| lead(SUBSCRIBE_INVOICES, config, pool, shutdown, subscribe_invoices)));
+    }
+    if jobs.contains(&Job::Notifications) {
+        supervisor = supervisor.add(DELIVER_NOTIFICATIONS, with_shared(&config, &pool, deliver_notifications));
+    }
+    if jobs.contains(&Job::Events) {
+        supervisor = supervisor.add(RELAY_EVENTS, with_shared(&config, &pool, relay_events));
+    }
+    if jobs.contains(&Job::Reconcile) {
+        supervisor = supervisor.add(RECONCILE_INVOICES, with_shared(&config, &pool, |config, pool, shutdown| lead(RECONCILE_INVOICES, config, pool, shutdown, reconcile_invoices)));
+    }
+    // separate leader locks, so replicas split with --only settle and --only expire each lead their own
+    for (job, name, checks) in [(Job::Settle, SETTLE_CHALLENGES, SETTLE), (Job::Expire, EXPIRE_CHALLENGES, EXPIRE)] {
+        if jobs.contains(&job) {
+            supervisor = supervisor.add(name, with_shared(&config, &pool, move |config, pool, shutdown| lead(name, config, pool, shutdown, move |config, pool, shutdown| db_checks(name, config, pool, shutdown, checks))));
+        }
+    }
+    supervisor.run(wait_for_signal(), config.shutdown_deadline()).await
+}
+
+// a single pass that still finishes its current challenge on SIGTERM
+async fn run_once<Fut>(pass: impl FnOnce(Shutdown) -> Fut) -> Result<(), String>
+    where Fut: Future<Output = JobResult> {
+    let (trigger, shutdown) = Shutdown::new();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        let _ = trigger.send(true);
+    });
+    pass(shutdown).await.map_err(|e| e.to_string())
+}
+
+// a dry run prints what the pass would have changed to stdout. logs go to stderr
+async fn db_checks_pass(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown, checks: Checks, report: Option<ReportFormat>) -> JobResult {
+    let changes = db_checks_once(config, pool, shutdown, checks, report.is_some()).await?;
+    if let Some(format) = report {
+        print!("{}", report::render(&changes, format));
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    let subscribe_task = tokio::spawn(async move {
-        subscribe_invoices().await
-    });
+    let command = Cli::parse().command();
+    init_logging();
+
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!(error = %e, "exiting");
+            exit(1);
+        }
+    };
+    info!(http_addr = %config.http.addr, lnd_url = %config.lnd.url, lichess_url = %config.lichess.url, "loaded config");
+
+    let pool = match db::connect(&config.db).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!(error = %e, "exiting");
+            exit(1);
+        }
+    };
+
+    // run migrates on its own and migrate is how a schema that's behind gets fixed
+    if !matches!(command, Command::Run { .. } | Command::Migrate) {
+        if let Err(e) = check_migrated(&pool).await {
+            error!(error = %e, "exiting");
+            exit(1);
+        }
+    }
 
-    db_checks().await;
+    let result = match command {
+        Command::Run { only } => run(config, pool, only).await,
+        Command::SettleOnce { dry_run } => run_once(|shutdown| db_checks_pass(config, pool, shutdown, SETTLE, dry_run.report())).await,
+        Command::ExpireOnce { dry_run } => run_once(|shutdown| db_checks_pass(config, pool, shutdown, EXPIRE, dry_run.report())).await,
+        Command::ReconcileOnce => run_once(|shutdown| reconcile_once(config, pool, shutdown)).await,
+        Command::Migrate => migrate(&pool).await.map_err(|e| e.to_string()),
+        Command::Settle { challenge_id, winner, note } => admin::settle(&config, &pool, challenge_id, &winner, &note).await.map_err(|e| e.to_string()),
+        Command::Refund { challenge_id, note } => admin::refund(&config, &pool, challenge_id, &note).await.map_err(|e| e.to_string()),
+        Command::Void { challenge_id, note } => admin::void(&config, &pool, challenge_id, &note).await.map_err(|e| e.to_string())
+    };
 
-    subscribe_task.await.unwrap();
+    if let Err(e) = result {
+        error!(error = %e, "exiting");
+        exit(1);
+    }
 }
@@ -0,0 +1,78 @@
+use std::sync::LazyLock;
+use prometheus::{register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder};
+use crate::sats::Sats;
+
+// outcome is one of settled, drawn, refunded, expired or failed
+pub static CHALLENGES: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "lightningchess_challenges_total", "Challenges processed by outcome", &["outcome"]).unwrap());
+
+pub static PENDING_CHALLENGES: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "lightningchess_pending_challenges", "Challenges waiting on the jobs by status", &["status"]).unwrap());
+
+pub static OLDEST_ACCEPTED_CHALLENGE_AGE: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "lightningchess_oldest_accepted_challenge_age_seconds", "Age of the oldest challenge in ACCEPTED status").unwrap());
+
+pub static SETTLEMENT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "lightningchess_settlement_duration_seconds", "Time to process one challenge", &["job"]).unwrap());
+
+pub static SATS_PAID_OUT: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "lightningchess_sats_paid_out_total", "Sats credited to players by settlement and refunds").unwrap());
+
+pub static FEES_COLLECTED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "lightningchess_fees_collected_sats_total", "Sats credited to the admin account as fees").unwrap());
+
+pub static INVOICES_SETTLED: LazyLock<IntCounter> = LazyLock::new(|| register_int_counter!(
+    "lightningchess_invoices_settled_total", "Invoices credited to balances").unwrap());
+
+// service is lichess or lnd
+pub static EXTERNAL_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| register_histogram_vec!(
+    "lightningchess_external_request_duration_seconds", "Latency of requests to lichess and lnd", &["service", "endpoint"]).unwrap());
+
+// code is the http status, or error when no response came back
+pub static EXTERNAL_REQUEST_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "lightningchess_external_request_errors_total", "Failed requests to lichess and lnd", &["service", "endpoint", "code"]).unwrap());
+
+pub static LND_SUBSCRIPTION_CONNECTED: LazyLock<IntGauge> = LazyLock::new(|| register_int_gauge!(
+    "lightningchess_lnd_subscription_connected", "1 while the lnd invoice stream is open").unwrap());
+
+pub static JOB_RESTARTS: LazyLock<IntCounterVec> = LazyLock::new(|| register_int_counter_vec!(
+    "lightningchess_job_restarts_total", "Times the supervisor restarted a job after an error or panic", &["job"]).unwrap());
+
+pub static JOB_LEADER: LazyLock<IntGaugeVec> = LazyLock::new(|| register_int_gauge_vec!(
+    "lightningchess_job_leader", "1 while this replica holds the job's leader lock", &["job"]).unwrap());
+
+pub fn record_payout(amount: Sats) {
+    SATS_PAID_OUT.inc_by(u64::try_from(amount.amount()).unwrap_or(0));
+}
+
+pub fn record_fee(amount: Sats) {
+    FEES_COLLECTED.inc_by(u64::try_from(amount.amount()).unwrap_or(0));
+}
+
+// counts an unsuccessful response or a request that never got one
+pub fn record_request_result(service: &str, endpoint: &str, result: &Result<reqwest::Response, reqwest::Error>) {
+    let code = match result {
+        Ok(response) if response.status().is_success() => return,
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_string()
+    };
+    EXTERNAL_REQUEST_ERRORS.with_label_values(&[service, endpoint, &code]).inc();
+}
+
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_includes_metrics() {
+        CHALLENGES.with_label_values(&["settled"]).inc();
+        let rendered = render();
+        assert!(rendered.contains("lightningchess_challenges_total{outcome=\"settled\"}"));
+    }
+}
@@ -0,0 +1,88 @@
+use sqlx::{Pool, Postgres};
+use sqlx::migrate::Migrator;
+use tracing::info;
+use crate::supervisor::JobError;
+
+// the schema the jobs rely on, embedded from migrations/ at compile time. sqlx holds an advisory
+// lock while migrating so replicas starting at the same time don't race
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub async fn migrate(pool: &Pool<Postgres>) -> Result<(), JobError> {
+    // taken out of the pool so the statement timeout can be lifted for slow index builds
+    let mut conn = pool.acquire().await?.detach();
+    sqlx::query("SET statement_timeout = 0").execute(&mut conn).await?;
+    MIGRATOR.run(&mut conn).await
+        .map_err(|e| JobError::Fatal(format!("can't migrate database: {}", e)))?;
+    let latest = MIGRATOR.iter().map(|migration| migration.version).max();
+    info!(?latest, "database migrated");
+    Ok(())
+}
+
+// every job, one-shot pass and admin command writes the outbox tables in the same transaction as its
+// ledger changes, so they refuse to start against a schema that's behind instead of rolling back
+// every settlement
+pub async fn check_migrated(pool: &Pool<Postgres>) -> Result<(), JobError> {
+    let applied = match sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success").fetch_all(pool).await {
+        Ok(applied) => applied,
+        // nothing has been migrated yet
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("42P01") => Vec::new(),
+        Err(e) => return Err(e.into())
+    };
+    let missing: Vec<i64> = MIGRATOR.iter()
+        .map(|migration| migration.version)
+        .filter(|version| !applied.contains(version))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(JobError::Fatal(format!("database is missing migrations {:?}. run migrate or set db.migrate_on_startup", missing)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::Executor;
+    use crate::test_support::empty_test_db;
+
+    // the tables as the web app created them, before the jobs owned any migrations
+    const WEB_APP_SCHEMA: &str = "
+        CREATE TABLE challenge (
+            id SERIAL PRIMARY KEY, username VARCHAR NOT NULL, time_limit INTEGER, opponent_time_limit INTEGER,
+            increment INTEGER, color VARCHAR, sats BIGINT, opp_username VARCHAR NOT NULL, status VARCHAR,
+            lichess_challenge_id VARCHAR, created_on TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc'), expire_after INTEGER
+        );
+        CREATE TABLE lightningchess_transaction (
+            transaction_id SERIAL PRIMARY KEY, username VARCHAR NOT NULL, ttype VARCHAR NOT NULL, detail VARCHAR NOT NULL,
+            amount BIGINT NOT NULL, state VARCHAR NOT NULL, preimage VARCHAR, payment_addr VARCHAR, payment_request VARCHAR,
+            payment_hash VARCHAR, lichess_challenge_id VARCHAR, created_on TIMESTAMP DEFAULT (now() AT TIME ZONE 'utc')
+        );
+        CREATE TABLE lightningchess_balance (username VARCHAR PRIMARY KEY, balance BIGINT NOT NULL DEFAULT 0);
+        INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id)
+            VALUES ('alice', 'challenge', 'stake', -100, 'SETTLED', 'abc');
+    ";
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn migrates_the_web_apps_existing_schema() {
+        let db = empty_test_db().await;
+        db.pool.execute(WEB_APP_SCHEMA).await.unwrap();
+        assert!(check_migrated(&db.pool).await.is_err());
+
+        migrate(&db.pool).await.unwrap();
+        check_migrated(&db.pool).await.unwrap();
+        let (applied,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&db.pool).await.unwrap();
+        assert_eq!(applied as usize, MIGRATOR.iter().count());
+        let (rows,) = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM lightningchess_transaction WHERE challenge_id IS NULL")
+            .fetch_one(&db.pool).await.unwrap();
+        // rows from before the column existed stay unlinked, outside the idempotency index
+        assert_eq!(rows, 1);
+        sqlx::query("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id) VALUES ('alice', 'challenge', 'stake', -100, 'SETTLED', 'abc')")
+            .execute(&db.pool).await.unwrap();
+        let linked = "INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, challenge_id) VALUES ('alice', 'expired', 'refund', 100, 'SETTLED', 1)";
+        sqlx::query(linked).execute(&db.pool).await.unwrap();
+        assert!(sqlx::query(linked).execute(&db.pool).await.is_err());
+        db.drop().await;
+    }
+}
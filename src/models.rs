@@ -1,10 +1,54 @@
-use std::error;
+use std::{error, fmt};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow};
 use chrono::NaiveDateTime;
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum SettlementError {
+    MissingSats { challenge_id: i32 },
+    NegativeStake { challenge_id: i32, sats: i64 },
+    Overflow { challenge_id: i32 },
+    NotAPlayer { challenge_id: i32, username: String },
+    MissingStake { challenge_id: i32 },
+    MissingColor { challenge_id: i32 }
+}
+
+impl fmt::Display for SettlementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettlementError::MissingSats { challenge_id } => write!(f, "challenge {} has no sats", challenge_id),
+            SettlementError::NegativeStake { challenge_id, sats } => write!(f, "challenge {} has negative stake {}", challenge_id, sats),
+            SettlementError::Overflow { challenge_id } => write!(f, "sats overflow settling challenge {}", challenge_id),
+            SettlementError::NotAPlayer { challenge_id, username } => write!(f, "{} is not a player in challenge {}", username, challenge_id),
+            SettlementError::MissingStake { challenge_id } => write!(f, "challenge {} has no stake in the ledger to refund", challenge_id),
+            SettlementError::MissingColor { challenge_id } => write!(f, "challenge {} has no color for its creator", challenge_id)
+        }
+    }
+}
 
-pub type LightningChessResult<T> = Result<T, Box<dyn error::Error>>;
+impl error::Error for SettlementError {}
+
+// reasons an operator command is refused
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminError {
+    NotFound { challenge_id: i32 },
+    Terminal { challenge_id: i32, status: String },
+    NotAccepted { challenge_id: i32, status: String },
+    UnknownWinner { challenge_id: i32, winner: String }
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminError::NotFound { challenge_id } => write!(f, "challenge {} not found", challenge_id),
+            AdminError::Terminal { challenge_id, status } => write!(f, "challenge {} is already {}", challenge_id, status),
+            AdminError::NotAccepted { challenge_id, status } => write!(f, "challenge {} is {}. only accepted challenges can be settled", challenge_id, status),
+            AdminError::UnknownWinner { challenge_id, winner } => write!(f, "{} is not a player in challenge {}. use a player's username or draw", winner, challenge_id)
+        }
+    }
+}
+
+impl error::Error for AdminError {}
 
 fn default_string() -> String {
     "".to_string()
@@ -28,6 +72,7 @@ pub struct Transaction {
     pub payment_request: Option<String>,
     pub payment_hash: Option<String>,
     pub lichess_challenge_id: Option<String>,
+    pub challenge_id: Option<i32>,
     pub created_on: Option<NaiveDateTime>
 }
 
@@ -49,6 +94,12 @@ pub struct Challenge {
     pub expire_after: Option<i32> // seconds
 }
 
+#[derive(Serialize, Deserialize, FromRow, Debug)]
+pub struct LedgerBalance {
+    pub username: String,
+    pub net_amount: i64
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct InvoiceResult {
     pub result: Invoice
@@ -0,0 +1,364 @@
+use std::sync::Arc;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::transport::smtp::authentication::Credentials;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, FromRow, Pool, Postgres};
+use sqlx::types::Json;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::config::{Config, Secret};
+use crate::control::{CONTROL, DELIVER_NOTIFICATIONS};
+use crate::error::{LightningChessError, LightningChessResult};
+use crate::sats::Sats;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
+use crate::throttle::{LICHESS_RATE_LIMIT_BACKOFF, LICHESS_THROTTLE};
+
+// notifications are written to lightningchess_notification_outbox in the same db transaction as the
+// ledger change they describe, one row per sink. the delivery job sends them and retries failures,
+// so nothing is lost if the process restarts between the commit and the send.
+const MAX_ATTEMPTS: i32 = 10;
+const BATCH_SIZE: usize = 100;
+// added to the send timeout for how long a claimed notification is left to the loop sending it.
+// only a sender that died mid-send leaves its claim to expire, and the notification is retried then
+const CLAIM_MARGIN_SECONDS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum NotificationEvent {
+    ChallengeSettled { challenge_id: i32, lichess_challenge_id: String, result: String, amount: Sats },
+    ChallengeRefunded { challenge_id: i32, lichess_challenge_id: String, amount: Sats },
+    ChallengeExpired { challenge_id: i32, amount: Sats },
+    ChallengeVoided { challenge_id: i32, amount: Sats },
+    ChallengeRefundedBySupport { challenge_id: i32, amount: Sats },
+    DepositCredited { amount: Sats }
+}
+
+impl NotificationEvent {
+    pub fn subject(&self) -> String {
+        match self {
+            NotificationEvent::ChallengeSettled { challenge_id, .. } => format!("Challenge {} settled", challenge_id),
+            NotificationEvent::ChallengeRefunded { challenge_id, .. } => format!("Challenge {} refunded", challenge_id),
+            NotificationEvent::ChallengeExpired { challenge_id, .. } => format!("Challenge {} expired", challenge_id),
+            NotificationEvent::ChallengeVoided { challenge_id, .. } => format!("Challenge {} cancelled", challenge_id),
+            NotificationEvent::ChallengeRefundedBySupport { challenge_id, .. } => format!("Challenge {} refunded", challenge_id),
+            NotificationEvent::DepositCredited { .. } => "Deposit received".to_string()
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            NotificationEvent::ChallengeSettled { lichess_challenge_id, result, amount, .. } if result == "draw" =>
+                format!("Your game https://lichess.org/{} was a draw. {} sats were returned to your balance.", lichess_challenge_id, amount),
+            NotificationEvent::ChallengeSettled { lichess_challenge_id, result, amount, .. } if result == "won" =>
+                format!("You won your game https://lichess.org/{}! {} sats were added to your balance.", lichess_challenge_id, amount),
+            NotificationEvent::ChallengeSettled { lichess_challenge_id, .. } =>
+                format!("You lost your game https://lichess.org/{}. Better luck next time.", lichess_challenge_id),
+            NotificationEvent::ChallengeRefunded { lichess_challenge_id, amount, .. } =>
+                format!("Your game https://lichess.org/{} was never played. {} sats were returned to your balance.", lichess_challenge_id, amount),
+            NotificationEvent::ChallengeExpired { challenge_id, amount } =>
+                format!("Challenge {} wasn't accepted in time. {} sats were returned to your balance.", challenge_id, amount),
+            NotificationEvent::ChallengeVoided { challenge_id, amount } =>
+                format!("Challenge {} was cancelled by support. {} sats were returned to your balance.", challenge_id, amount),
+            NotificationEvent::ChallengeRefundedBySupport { challenge_id, amount } =>
+                format!("Challenge {} was refunded by support. {} sats were returned to your balance.", challenge_id, amount),
+            NotificationEvent::DepositCredited { amount } =>
+                format!("Your deposit of {} sats was added to your balance.", amount)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Webhook,
+    Email,
+    Lichess
+}
+
+impl Channel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Webhook => "webhook",
+            Channel::Email => "email",
+            Channel::Lichess => "lichess"
+        }
+    }
+}
+
+pub enum Sink {
+    // POSTs every event as json to a single url
+    Webhook { client: Client, url: String },
+    // sends to the address in lightningchess_notification_email. users without one are skipped
+    Email { mailer: AsyncSmtpTransport<Tokio1Executor>, from: String },
+    // lichess private message from the site account. usernames are lichess usernames
+    // shares the settlement job's throttle, since lichess rate limits the account and not the endpoint
+    Lichess { client: Client, url: String, token: Secret, interval: Duration }
+}
+
+impl Sink {
+    pub fn channel(&self) -> Channel {
+        match self {
+            Sink::Webhook { .. } => Channel::Webhook,
+            Sink::Email { .. } => Channel::Email,
+            Sink::Lichess { .. } => Channel::Lichess
+        }
+    }
+
+    async fn send(&self, pool: &Pool<Postgres>, username: &String, event: &NotificationEvent) -> LightningChessResult<()> {
+        match self {
+            Sink::Webhook { client, url } => {
+                let body = WebhookBody { username, event };
+                client
+                    .post(url)
+                    .json(&body)
+                    .send().await
+                    .and_then(|resp| resp.error_for_status())
+                    .map_err(LightningChessError::webhook)?;
+            }
+            Sink::Email { mailer, from } => {
+                let email = sqlx::query_scalar::<_, String>("SELECT email FROM lightningchess_notification_email WHERE username=$1")
+                    .bind(username)
+                    .fetch_optional(pool).await?;
+                match email {
+                    Some(email) => {
+                        let message = Message::builder()
+                            .from(from.parse().map_err(|e| LightningChessError::Config(format!("invalid from address: {}", e)))?)
+                            .to(email.parse().map_err(|e| LightningChessError::Parse(format!("invalid email address: {}", e)))?)
+                            .subject(event.subject())
+                            .body(event.message())
+                            .map_err(|e| LightningChessError::Parse(e.to_string()))?;
+                        mailer.send(message).await.map_err(LightningChessError::email)?;
+                    }
+                    None => debug!(%username, "no email address. skipping")
+                }
+            }
+            Sink::Lichess { client, url, token, interval } => {
+                LICHESS_THROTTLE.wait(*interval).await;
+                let resp = client
+                    .post(format!("{}/inbox/{}", url, username))
+                    .bearer_auth(token.expose())
+                    .form(&[("text", event.message())])
+                    .send().await
+                    .map_err(LightningChessError::lichess)?;
+                if resp.status().as_u16() == 429 {
+                    warn!("lichess rate limit hit. holding every request back for a minute");
+                    LICHESS_THROTTLE.back_off(LICHESS_RATE_LIMIT_BACKOFF);
+                }
+                resp.error_for_status().map_err(LightningChessError::lichess)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    username: &'a String,
+    event: &'a NotificationEvent
+}
+
+#[derive(FromRow)]
+struct OutboxNotification {
+    id: i64,
+    username: String,
+    channel: String,
+    payload: Json<NotificationEvent>,
+    attempts: i32
+}
+
+pub struct Notifier {
+    sinks: Vec<Sink>,
+    claim_seconds: i64
+}
+
+impl Notifier {
+    // each sink is enabled by its section of the config
+    pub fn from_config(config: &Config) -> Result<Notifier, JobError> {
+        let timeout = config.notifications.request_timeout();
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| JobError::Fatal(format!("can't build notification client: {}", e)))?;
+        let mut sinks = Vec::new();
+        if let Some(url) = &config.notifications.webhook_url {
+            sinks.push(Sink::Webhook { client: client.clone(), url: url.clone() });
+        }
+        if let Some(smtp) = &config.notifications.smtp {
+            let credentials = Credentials::new(smtp.username.clone(), smtp.password.expose().to_string());
+            let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)
+                .map_err(|e| JobError::Fatal(format!("invalid smtp host: {}", e)))?
+                .credentials(credentials)
+                .timeout(Some(timeout))
+                .build();
+            sinks.push(Sink::Email { mailer, from: smtp.from.clone() });
+        }
+        if let Some(token) = &config.lichess.message_token {
+            sinks.push(Sink::Lichess { client, url: config.lichess.url.clone(), token: token.clone(), interval: config.lichess.request_interval() });
+        }
+        let claim_seconds = (config.notifications.request_timeout_seconds + CLAIM_MARGIN_SECONDS) as i64;
+        Ok(Notifier { sinks, claim_seconds })
+    }
+
+    fn sink(&self, channel: &str) -> Option<&Sink> {
+        self.sinks.iter().find(|sink| sink.channel().as_str() == channel)
+    }
+
+    // queue the event for every enabled sink. call with the transaction that makes the change
+    pub async fn enqueue(&self, tx: &mut sqlx::Transaction<'_, Postgres>, username: &String, event: &NotificationEvent) -> Result<(), Error> {
+        for sink in self.sinks.iter() {
+            sqlx::query("INSERT INTO lightningchess_notification_outbox (username, channel, payload) VALUES ($1, $2, $3)")
+                .bind(username)
+                .bind(sink.channel().as_str())
+                .bind(Json(event))
+                .execute(&mut *tx).await?;
+        }
+        Ok(())
+    }
+}
+
+// seconds to wait before the next attempt. doubles each time up to an hour
+fn retry_backoff_seconds(attempts: i32) -> i64 {
+    let exponent = attempts.clamp(0, 7) as u32;
+    (30 * 2_i64.pow(exponent)).min(3_600)
+}
+
+// claims the oldest notification that's due by pushing its next attempt past the send. the row lock
+// only lasts for this statement, so nothing is held open while the sinks are called
+async fn claim_next(pool: &Pool<Postgres>, claim_seconds: i64) -> Result<Option<OutboxNotification>, Error> {
+    sqlx::query_as::<_, OutboxNotification>("UPDATE lightningchess_notification_outbox SET next_attempt_at=(now() AT TIME ZONE 'UTC') + $2 * interval '1 second' WHERE id=(SELECT id FROM lightningchess_notification_outbox WHERE delivered_on IS NULL AND attempts < $1 AND next_attempt_at <= (now() AT TIME ZONE 'UTC') ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING id, username, channel, payload, attempts")
+        .bind(MAX_ATTEMPTS)
+        .bind(claim_seconds)
+        .fetch_optional(pool).await
+}
+
+// sends up to a batch of due notifications, recording each one as soon as it's sent so a failure
+// later in the batch can't make it go out twice
+async fn deliver_pending(pool: &Pool<Postgres>, notifier: &Notifier) -> LightningChessResult<usize> {
+    let mut num_notifications = 0;
+    while num_notifications < BATCH_SIZE {
+        let Some(notification) = claim_next(pool, notifier.claim_seconds).await? else { break };
+        num_notifications += 1;
+
+        let result = match notifier.sink(&notification.channel) {
+            Some(sink) => sink.send(pool, &notification.username, &notification.payload).await,
+            None => Err(LightningChessError::Config(format!("no {} sink configured", notification.channel)))
+        };
+
+        match result {
+            Ok(_) => {
+                info!(notification_id = notification.id, channel = %notification.channel, username = %notification.username, "delivered notification");
+                sqlx::query("UPDATE lightningchess_notification_outbox SET delivered_on=(now() AT TIME ZONE 'UTC') WHERE id=$1")
+                    .bind(notification.id)
+                    .execute(pool).await?;
+            }
+            Err(e) => {
+                warn!(notification_id = notification.id, channel = %notification.channel, username = %notification.username, error = %e, retryable = e.is_retryable(), "error delivering notification");
+                // a refused message or a bad address fails the same way every time, so it's given up on now
+                let attempts = if e.is_retryable() { notification.attempts + 1 } else { MAX_ATTEMPTS };
+                let backoff = retry_backoff_seconds(notification.attempts);
+                sqlx::query("UPDATE lightningchess_notification_outbox SET attempts=$1, last_error=$2, next_attempt_at=(now() AT TIME ZONE 'UTC') + $3 * interval '1 second' WHERE id=$4")
+                    .bind(attempts)
+                    .bind(e.to_string())
+                    .bind(backoff)
+                    .bind(notification.id)
+                    .execute(pool).await?;
+            }
+        }
+    }
+    Ok(num_notifications)
+}
+
+pub async fn deliver_notifications(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    info!("starting notification delivery");
+
+    let notifier = Notifier::from_config(&config)?;
+    let mut loop_count = 1;
+    loop {
+        CONTROL.wait_while_paused(DELIVER_NOTIFICATIONS, &shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        async {
+            match deliver_pending(&pool, &notifier).await {
+                Ok(num_notifications) => debug!(num_notifications, "delivered notifications"),
+                Err(e) if e.is_retryable() => warn!(error = %e, "error delivering notifications. retrying next pass"),
+                Err(e) => error!(error = %e, "error delivering notifications")
+            }
+        }.instrument(info_span!("notification_delivery_loop", loop_count)).await;
+        loop_count += 1;
+
+        tokio::select! {
+            _ = sleep(config.notifications.poll_interval()) => (),
+            _ = shutdown.requested() => ()
+        }
+    }
+    info!("notification delivery stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json as JsonBody, Router};
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use serde_json::Value;
+    use crate::test_support::{serve, test_db};
+
+    // accepts alice's notifications and refuses bob's
+    fn webhook_stub() -> String {
+        async fn receive(JsonBody(body): JsonBody<Value>) -> StatusCode {
+            if body["username"] == "alice" { StatusCode::OK } else { StatusCode::BAD_REQUEST }
+        }
+        serve(Router::new().route("/hook", post(receive)))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn records_each_delivery_on_its_own() {
+        let db = test_db().await;
+        let mut config = db.config("", "");
+        config.notifications.webhook_url = Some(format!("{}/hook", webhook_stub()));
+        let notifier = Notifier::from_config(&config).unwrap();
+        let mut tx = db.pool.begin().await.unwrap();
+        for username in ["alice", "bob"] {
+            notifier.enqueue(&mut tx, &username.to_string(), &NotificationEvent::DepositCredited { amount: Sats::new(1) }).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        assert_eq!(deliver_pending(&db.pool, &notifier).await.unwrap(), 2);
+        let rows = sqlx::query_as::<_, (String, bool, i32)>("SELECT username, delivered_on IS NOT NULL, attempts FROM lightningchess_notification_outbox ORDER BY id")
+            .fetch_all(&db.pool).await.unwrap();
+        // the refusal is permanent so bob's isn't retried
+        assert_eq!(rows, [("alice".to_string(), true, 0), ("bob".to_string(), false, MAX_ATTEMPTS)]);
+        assert_eq!(deliver_pending(&db.pool, &notifier).await.unwrap(), 0);
+        db.drop().await;
+    }
+
+    #[test]
+    fn retry_backoff() {
+        assert_eq!(retry_backoff_seconds(0), 30);
+        assert_eq!(retry_backoff_seconds(1), 60);
+        assert_eq!(retry_backoff_seconds(3), 240);
+        assert_eq!(retry_backoff_seconds(7), 3_600);
+        assert_eq!(retry_backoff_seconds(100), 3_600);
+    }
+
+    #[test]
+    fn settled_messages() {
+        let won = NotificationEvent::ChallengeSettled { challenge_id: 1, lichess_challenge_id: "abc".to_string(), result: "won".to_string(), amount: Sats::new(196) };
+        assert_eq!(won.message(), "You won your game https://lichess.org/abc! 196 sats were added to your balance.");
+        let draw = NotificationEvent::ChallengeSettled { challenge_id: 1, lichess_challenge_id: "abc".to_string(), result: "draw".to_string(), amount: Sats::new(98) };
+        assert_eq!(draw.message(), "Your game https://lichess.org/abc was a draw. 98 sats were returned to your balance.");
+        let lost = NotificationEvent::ChallengeSettled { challenge_id: 1, lichess_challenge_id: "abc".to_string(), result: "lost".to_string(), amount: Sats::ZERO };
+        assert_eq!(lost.subject(), "Challenge 1 settled");
+        assert_eq!(lost.message(), "You lost your game https://lichess.org/abc. Better luck next time.");
+    }
+
+    #[test]
+    fn event_payload() {
+        let event = NotificationEvent::DepositCredited { amount: Sats::new(1_000) };
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"type":"DepositCredited","amount":1000}"#);
+    }
+}
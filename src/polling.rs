@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use chrono::Utc;
+use serde::Serialize;
+
+// what the settlement job last saw for each accepted challenge.
+// written by db_checks, read by the admin api. kept in memory so it starts over on restart
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PollState {
+    // lichess 404s so far
+    pub misses: i32,
+    // unix seconds of the first 404 since lichess last returned the game. the challenge is
+    // refunded as unplayed once missing_game_after_seconds have passed since
+    pub first_missed: Option<i64>,
+    // unix seconds
+    pub last_checked: Option<i64>,
+    pub last_game_status: Option<String>,
+    pub last_error: Option<String>
+}
+
+// keyed by challenge id
+#[derive(Default)]
+pub struct Polling {
+    challenges: Mutex<HashMap<i32, PollState>>
+}
+
+pub static POLLING: LazyLock<Polling> = LazyLock::new(Polling::default);
+
+impl Polling {
+    fn update<T>(&self, challenge_id: i32, f: impl FnOnce(&mut PollState) -> T) -> T {
+        let mut challenges = self.challenges.lock().unwrap();
+        let state = challenges.entry(challenge_id).or_default();
+        state.last_checked = Some(Utc::now().timestamp());
+        f(state)
+    }
+
+    // returns how long lichess has been missing the game, counting from its first 404
+    pub fn record_miss(&self, challenge_id: i32) -> Duration {
+        self.update(challenge_id, |state| {
+            state.misses += 1;
+            let now = Utc::now().timestamp();
+            let first_missed = *state.first_missed.get_or_insert(now);
+            Duration::from_secs((now - first_missed).max(0) as u64)
+        })
+    }
+
+    // lichess has the game, so a later 404 starts timing from scratch
+    pub fn record_game_status(&self, challenge_id: i32, status: &str) {
+        self.update(challenge_id, |state| {
+            state.first_missed = None;
+            state.last_game_status = Some(status.to_string());
+        });
+    }
+
+    pub fn record_success(&self, challenge_id: i32) {
+        self.update(challenge_id, |state| state.last_error = None);
+    }
+
+    pub fn record_error(&self, challenge_id: i32, error: String) {
+        self.update(challenge_id, |state| state.last_error = Some(error));
+    }
+
+    // drops challenges that are no longer waiting on lichess
+    pub fn retain(&self, challenge_ids: &[i32]) {
+        self.challenges.lock().unwrap().retain(|challenge_id, _| challenge_ids.contains(challenge_id));
+    }
+
+    pub fn get(&self, challenge_id: i32) -> Option<PollState> {
+        self.challenges.lock().unwrap().get(&challenge_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_misses_and_errors() {
+        let polling = Polling::default();
+        assert_eq!(polling.record_miss(1), Duration::ZERO);
+        let first_missed = polling.get(1).unwrap().first_missed;
+        assert!(polling.record_miss(1) < Duration::from_secs(5));
+        polling.record_error(1, "timed out".to_string());
+        let state = polling.get(1).unwrap();
+        assert_eq!(state.misses, 2);
+        // later misses don't move the start
+        assert_eq!(state.first_missed, first_missed);
+        assert_eq!(state.last_error, Some("timed out".to_string()));
+        assert!(state.last_checked.is_some());
+
+        polling.record_success(1);
+        assert_eq!(polling.get(1).unwrap().last_error, None);
+    }
+
+    #[test]
+    fn finding_the_game_resets_the_miss_timer() {
+        let polling = Polling::default();
+        polling.record_miss(1);
+        polling.record_game_status(1, "started");
+        let state = polling.get(1).unwrap();
+        assert_eq!(state.first_missed, None);
+        assert_eq!(state.misses, 1);
+        assert_eq!(polling.record_miss(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn retain_drops_finished_challenges() {
+        let polling = Polling::default();
+        polling.record_game_status(1, "started");
+        polling.record_game_status(2, "started");
+        polling.retain(&[2]);
+        assert_eq!(polling.get(1), None);
+        assert_eq!(polling.get(2).unwrap().last_game_status, Some("started".to_string()));
+    }
+}
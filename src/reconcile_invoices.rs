@@ -1,65 +1,136 @@
-use std::env;
+use std::sync::Arc;
 use chrono::{NaiveDateTime, Utc};
 use reqwest::Client;
 use sqlx::{Pool, Postgres};
-use crate::models::{LightningChessResult, LookupInvoiceResponse, Transaction};
-// this serves as a backup to the streaming
-// if the invoice streaming goes down, this should be able to reconcile invoices
-pub async fn _reconcile(pool: &Pool<Postgres>) -> LightningChessResult<usize> {
-    // look up all the transactions that are in OPEN status
-    let transactions = sqlx::query_as::<_, Transaction>("SELECT * FROM lightningchess_transaction WHERE state='OPEN' LIMIT 1000")
-        .fetch_all(pool).await?;
-
-    let num_transactions = transactions.len();
-    println!("num_transactions: {}", num_transactions);
+use tokio::time::{sleep, Instant};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
+use crate::metrics;
+use crate::config::{Config, LndConfig};
+use crate::control::{CONTROL, RECONCILE_INVOICES};
+use crate::error::{LightningChessError, LightningChessResult};
+use crate::models::{LookupInvoiceResponse, Transaction};
+use crate::repository::{PAGE_SIZE, open_transactions};
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
 
+// this serves as a backup to the streaming
+// if the invoice streaming goes down, this should be able to reconcile invoices.
+// walks every open transaction oldest first, a page at a time, until the backlog or the pass budget runs out.
+// a retryable error, like lnd being down, stops the pass. a transaction that can't be looked up is skipped
+async fn reconcile(pool: &Pool<Postgres>, client: &Client, config: &Config, shutdown: &Shutdown) -> LightningChessResult<usize> {
+    let deadline = Instant::now() + config.reconcile.pass_budget();
     // unix time
     let current_seconds = Utc::now().timestamp();
-    let macaroon = env::var("LND_MACAROON").unwrap();
-    for transaction in transactions.iter() {
-        println!("processing transaction {}", serde_json::to_string(transaction).unwrap());
-        let mut _tx = pool.begin().await?;
-        let created_on: NaiveDateTime = transaction.created_on.unwrap();
-        let transaction_seconds = created_on.timestamp();
-        let diff_seconds = &current_seconds - transaction_seconds;
-        let transaction_id = &transaction.transaction_id;
-        println!("transaction: {transaction_id} diff_seconds: {diff_seconds}");
-        // 30 min to seconds = 1800. this is default invoice expiry time. add a little bit to not interfere with streaming
-        if diff_seconds > 2_000 {
-            // check lnd to see status
-            let payment_addr = transaction.payment_addr.as_ref().unwrap();
-            let base64_decoded_bytes = base64::decode(payment_addr).unwrap();
-            let base64_url_safe_encoded = base64::encode_config(base64_decoded_bytes, base64::URL_SAFE);
-            println!("base64_url_safe_encoded: {}", base64_url_safe_encoded);
-            let response = Client::new()
-                .get(format!("https://lightningchess.m.voltageapp.io:8080/v2/invoices/lookup?payment_addr={}", base64_url_safe_encoded))
-                .header("Grpc-Metadata-macaroon", &macaroon)
-                .send().await;
+    let mut num_transactions = 0;
+    let mut after = 0;
+    'pages: loop {
+        // look up the transactions that are in OPEN status
+        let transactions = open_transactions(pool, after).await?;
+        debug!(after, num_transactions = transactions.len(), "reconciling page of open transactions");
 
-            match response {
-                Ok(res) => {
-                    //println!("Status: {}", res.status());
-                    //println!("Headers:\n{:#?}", res.headers());
-                    let text = res.text().await;
-                    match text {
-                        Ok(text) => {
-                            //println!("text: {}", text);
-                            let lookup_invoice_response: LookupInvoiceResponse = serde_json::from_str(&text).unwrap();
-                            println!("lookupInvoiceResponse: {}", serde_json::to_string(&lookup_invoice_response).unwrap());
-                            // if expired then set to expired
-
-                            // if paid then pay out
-                        }
-                        Err(e) => {
-                            println!("error in text() :\n{}", e);
-                        }
-                    }
-                },
-                Err(e) => {
-                    println!("error from lnd lookup_invoice\n{}", e);
+        for transaction in transactions.iter() {
+            if shutdown.is_requested() {
+                info!("shutdown requested. leaving remaining transactions for the next run");
+                break 'pages;
+            }
+            if Instant::now() >= deadline {
+                warn!(num_transactions, "pass budget used up. leaving remaining transactions for the next run");
+                break 'pages;
+            }
+            num_transactions += 1;
+            if let Err(e) = reconcile_transaction(client, &config.lnd, current_seconds, transaction).await {
+                let e = e.for_invoice(transaction.payment_addr.as_deref().unwrap_or(""));
+                if e.is_retryable() {
+                    return Err(e);
                 }
-            };
+                error!(transaction_id = transaction.transaction_id, error = %e, "skipping transaction");
+            }
+        }
+
+        match transactions.last() {
+            Some(last) if transactions.len() as i64 == PAGE_SIZE => after = last.transaction_id,
+            _ => break
         }
     }
+    info!(num_transactions, "reconciled open transactions");
     Ok(num_transactions)
 }
+
+#[instrument(skip_all, fields(transaction_id = transaction.transaction_id, username = %transaction.username, payment_addr = ?transaction.payment_addr))]
+async fn reconcile_transaction(client: &Client, lnd: &LndConfig, current_seconds: i64, transaction: &Transaction) -> LightningChessResult<()> {
+    let created_on: NaiveDateTime = transaction.created_on
+        .ok_or_else(|| LightningChessError::InvariantViolation(format!("transaction {} has no created_on", transaction.transaction_id)))?;
+    let transaction_seconds = created_on.and_utc().timestamp();
+    let diff_seconds = current_seconds - transaction_seconds;
+    debug!(diff_seconds, "processing transaction");
+    // 30 min to seconds = 1800. this is default invoice expiry time. add a little bit to not interfere with streaming
+    if diff_seconds > 2_000 {
+        // check lnd to see status
+        let payment_addr = transaction.payment_addr.as_ref()
+            .ok_or_else(|| LightningChessError::InvariantViolation(format!("open transaction {} has no payment_addr", transaction.transaction_id)))?;
+        let base64_decoded_bytes = base64::decode(payment_addr)
+            .map_err(|e| LightningChessError::Parse(format!("invalid payment_addr: {}", e)))?;
+        let base64_url_safe_encoded = base64::encode_config(base64_decoded_bytes, base64::URL_SAFE);
+        let request_timer = metrics::EXTERNAL_REQUEST_DURATION.with_label_values(&["lnd", "invoices_lookup"]).start_timer();
+        let response = client
+            .get(format!("{}/v2/invoices/lookup?payment_addr={}", lnd.url, base64_url_safe_encoded))
+            .header("Grpc-Metadata-macaroon", lnd.macaroon.expose())
+            .send().await;
+        request_timer.observe_duration();
+        metrics::record_request_result("lnd", "invoices_lookup", &response);
+
+        let text = response
+            .and_then(|res| res.error_for_status())
+            .map_err(LightningChessError::lnd)?
+            .text().await
+            .map_err(LightningChessError::lnd)?;
+        let lookup_invoice_response: LookupInvoiceResponse = serde_json::from_str(&text)?;
+        debug!(state = %lookup_invoice_response.state, amt_paid_sat = %lookup_invoice_response.amt_paid_sat, "looked up invoice");
+        // if expired then set to expired
+
+        // if paid then pay out
+    }
+    Ok(())
+}
+
+fn lnd_client(config: &Config) -> Result<Client, JobError> {
+    Client::builder()
+        .timeout(config.lnd.request_timeout())
+        .build()
+        .map_err(|e| JobError::Fatal(format!("can't build lnd client: {}", e)))
+}
+
+pub async fn reconcile_invoices(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    info!("starting invoice reconciliation");
+    let client = lnd_client(&config)?;
+
+    let mut loop_count = 1;
+    loop {
+        CONTROL.wait_while_paused(RECONCILE_INVOICES, &shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        async {
+            match reconcile(&pool, &client, &config, &shutdown).await {
+                Ok(num_transactions) => debug!(num_transactions, "reconciled transactions"),
+                Err(e) if e.is_retryable() => warn!(error = %e, "error reconciling transactions. retrying next pass"),
+                Err(e) => error!(error = %e, "error reconciling transactions")
+            }
+        }.instrument(info_span!("reconcile_loop", loop_count)).await;
+        loop_count += 1;
+
+        tokio::select! {
+            _ = sleep(config.reconcile.poll_interval()) => (),
+            _ = shutdown.requested() => ()
+        }
+    }
+    info!("invoice reconciliation stopped");
+    Ok(())
+}
+
+pub async fn reconcile_once(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    let client = lnd_client(&config)?;
+    reconcile(&pool, &client, &config, &shutdown).await
+        .map(|_| ())
+        .map_err(|e| JobError::Failed(format!("error reconciling transactions: {}", e)))
+}
@@ -0,0 +1,79 @@
+use crate::cli::ReportFormat;
+use crate::settlement::SettlementPlan;
+
+// dry run reports. what each settlement plan would have written
+
+pub fn render(plans: &[SettlementPlan], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(plans).unwrap_or_default() + "\n",
+        ReportFormat::Table => render_table(plans)
+    }
+}
+
+// one row per change, columns padded to the widest value
+fn render_table(plans: &[SettlementPlan]) -> String {
+    let mut rows = vec![["CHALLENGE", "CHANGE", "USERNAME", "AMOUNT", "DETAIL"].map(String::from)];
+    for plan in plans {
+        let id = plan.challenge_id.to_string();
+        for insert in &plan.ledger {
+            let detail = format!("{} {}: {}", insert.state, insert.ttype, insert.detail);
+            rows.push([id.clone(), "ledger".to_string(), insert.username.clone(), insert.amount.to_string(), detail]);
+        }
+        for balance in &plan.balances {
+            rows.push([id.clone(), "balance".to_string(), balance.username.clone(), balance.amount.to_string(), String::new()]);
+        }
+        rows.push([id.clone(), "status".to_string(), String::new(), String::new(), format!("{} -> {}", plan.status.from, plan.status.to)]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:width$}", cell)).collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Challenge;
+    use crate::settlement::{plan_settlement, Outcome};
+    use crate::test_support::test_challenge;
+
+    fn get_plan() -> SettlementPlan {
+        let challenge = Challenge {
+            id: 7,
+            status: Some("ACCEPTED".to_string()),
+            lichess_challenge_id: Some("abc".to_string()),
+            ..test_challenge()
+        };
+        plan_settlement(&challenge, &Outcome::Win("user2".to_string()), 200, "admin", "lichess game").unwrap()
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(render(&[get_plan()], ReportFormat::Table), "\
+CHALLENGE  CHANGE   USERNAME  AMOUNT  DETAIL
+7          ledger   admin     4       SETTLED fee: fee from challenge 7
+7          ledger   user2     196     SETTLED winnings: lichess game
+7          balance  admin     4
+7          balance  user2     196
+7          status                     ACCEPTED -> COMPLETED
+");
+    }
+
+    #[test]
+    fn json() {
+        let json: serde_json::Value = serde_json::from_str(&render(&[get_plan()], ReportFormat::Json)).unwrap();
+        assert_eq!(json[0]["challenge_id"], 7);
+        assert_eq!(json[0]["balances"][1]["amount"], 196);
+        assert_eq!(json[0]["status"]["to"], "COMPLETED");
+    }
+}
@@ -0,0 +1,127 @@
+use sqlx::{Error, Pool, Postgres};
+use sqlx::postgres::PgQueryResult;
+use crate::models::{Challenge, LedgerBalance, Transaction};
+use crate::settlement::{BalanceChange, LedgerInsert, StatusChange};
+
+// the queries the jobs run, checked against the schema at compile time. without a DATABASE_URL
+// the macros read sqlx-data.json instead, so after changing a query run `cargo sqlx prepare`
+// against a migrated database and commit the result
+
+type DbTransaction<'c> = sqlx::Transaction<'c, Postgres>;
+
+// the scans page through their backlog by id. ids are handed out in creation order, so each pass
+// works oldest first and a page is a range of the status index rather than an offset. at the default
+// lichess rate of one request a second a page of accepted challenges fits in the default pass budget
+pub const PAGE_SIZE: i64 = 250;
+
+// challenges waiting on the lichess game, after the given id
+pub async fn accepted_challenges(pool: &Pool<Postgres>, after: i32) -> Result<Vec<Challenge>, Error> {
+    sqlx::query_as!(Challenge, "SELECT id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after FROM challenge WHERE status='ACCEPTED' AND id > $1 ORDER BY id LIMIT $2",
+        after, PAGE_SIZE)
+        .fetch_all(pool).await
+}
+
+// challenges never accepted within their expire_after, after the given id. created_on is stored
+// in UTC without a time zone
+pub async fn expired_challenges(pool: &Pool<Postgres>, default_expire_after: i32, after: i32) -> Result<Vec<Challenge>, Error> {
+    sqlx::query_as!(Challenge, "SELECT id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after FROM challenge WHERE status='WAITING FOR ACCEPTANCE' AND created_on + COALESCE(expire_after, $1) * interval '1 second' < (now() AT TIME ZONE 'UTC') AND id > $2 ORDER BY id LIMIT $3",
+        default_expire_after, after, PAGE_SIZE)
+        .fetch_all(pool).await
+}
+
+pub struct PendingChallenges {
+    pub status: String,
+    pub count: i64,
+    // seconds since the oldest one was created
+    pub oldest_age: i64
+}
+
+pub async fn pending_challenges(pool: &Pool<Postgres>) -> Result<Vec<PendingChallenges>, Error> {
+    sqlx::query_as!(PendingChallenges, r#"SELECT status AS "status!", COUNT(*) AS "count!", COALESCE(EXTRACT(EPOCH FROM (now() AT TIME ZONE 'UTC') - MIN(created_on)), 0)::BIGINT AS "oldest_age!" FROM challenge WHERE status IN ('ACCEPTED', 'WAITING FOR ACCEPTANCE') GROUP BY status"#)
+        .fetch_all(pool).await
+}
+
+// challenges the jobs still have to finish, for the admin api
+pub async fn active_challenges(pool: &Pool<Postgres>) -> Result<Vec<Challenge>, Error> {
+    sqlx::query_as!(Challenge, "SELECT id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after FROM challenge WHERE status IN ('ACCEPTED', 'WAITING FOR ACCEPTANCE') ORDER BY id")
+        .fetch_all(pool).await
+}
+
+pub async fn get_challenge(pool: &Pool<Postgres>, challenge_id: i32) -> Result<Option<Challenge>, Error> {
+    sqlx::query_as!(Challenge, "SELECT id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after FROM challenge WHERE id=$1",
+        challenge_id)
+        .fetch_optional(pool).await
+}
+
+// an operator command waits for the row instead of skipping it, so the settlement job can't finish
+// the challenge at the same time. the command checks the status itself
+pub async fn lock_challenge_row(tx: &mut DbTransaction<'_>, challenge_id: i32) -> Result<Option<Challenge>, Error> {
+    sqlx::query_as!(Challenge, "SELECT id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after FROM challenge WHERE id=$1 FOR UPDATE",
+        challenge_id)
+        .fetch_optional(tx).await
+}
+
+// locks an accepted challenge for the rest of the transaction. None when another worker, replica
+// or operator holds it, or it's no longer in that status
+pub async fn lock_challenge(tx: &mut DbTransaction<'_>, challenge_id: i32, status: &str) -> Result<Option<i32>, Error> {
+    sqlx::query_scalar!("SELECT id FROM challenge WHERE id=$1 AND status=$2 FOR UPDATE SKIP LOCKED",
+        challenge_id, status)
+        .fetch_optional(tx).await
+}
+
+// every ledger row written by the jobs is linked to the challenge it belongs to
+pub async fn insert_ledger_row(tx: &mut DbTransaction<'_>, challenge: &Challenge, insert: &LedgerInsert) -> Result<PgQueryResult, Error> {
+    sqlx::query!("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id, challenge_id) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        insert.username, insert.ttype, insert.detail, insert.amount.amount(), insert.state, challenge.lichess_challenge_id, challenge.id)
+        .execute(tx).await
+}
+
+// net settled ledger amount per user for a challenge. a stake debit is negative. the web app writes
+// stakes without challenge_id, so unlinked rows with the challenge's lichess id count too. that's the
+// only key they share with the challenge. without a lichess id they don't show up, and the planner
+// leaves the challenge for an operator rather than guess which rows are its stakes
+pub async fn get_challenge_ledger(tx: &mut DbTransaction<'_>, challenge: &Challenge) -> Result<Vec<LedgerBalance>, Error> {
+    sqlx::query_as!(LedgerBalance, r#"SELECT username, SUM(amount)::BIGINT AS "net_amount!" FROM lightningchess_transaction WHERE (challenge_id=$1 OR (challenge_id IS NULL AND lichess_challenge_id=$2)) AND state='SETTLED' GROUP BY username"#,
+        challenge.id, challenge.lichess_challenge_id)
+        .fetch_all(tx).await
+}
+
+// a credit to a player without a balance row yet creates it like a first deposit, instead of
+// updating nothing while the ledger row still commits
+pub async fn add_to_balance(tx: &mut DbTransaction<'_>, change: &BalanceChange) -> Result<PgQueryResult, Error> {
+    credit_balance(tx, &change.username, change.amount.amount()).await
+}
+
+// the status check makes a second settlement of the same challenge fail with RowNotFound, rolling it back
+pub async fn change_status(tx: &mut DbTransaction<'_>, challenge_id: i32, change: &StatusChange) -> Result<Challenge, Error> {
+    sqlx::query_as!(Challenge, "UPDATE challenge SET status=$3 WHERE id=$1 AND status=$2 RETURNING id, username, time_limit, opponent_time_limit, increment, color, sats, opp_username, status, lichess_challenge_id, created_on, expire_after",
+        challenge_id, change.from, change.to)
+        .fetch_one(tx).await
+}
+
+// locks the deposit an invoice pays until the transaction ends
+pub async fn lock_deposit(tx: &mut DbTransaction<'_>, payment_addr: &str) -> Result<Transaction, Error> {
+    sqlx::query_as!(Transaction, "SELECT transaction_id, username, ttype, detail, amount, state, preimage, payment_addr, payment_request, payment_hash, lichess_challenge_id, challenge_id, created_on FROM lightningchess_transaction WHERE payment_addr=$1 FOR UPDATE",
+        payment_addr)
+        .fetch_one(tx).await
+}
+
+pub async fn settle_deposit(tx: &mut DbTransaction<'_>, transaction_id: i32, amount: i64) -> Result<PgQueryResult, Error> {
+    sqlx::query!("UPDATE lightningchess_transaction SET state='SETTLED', amount=$1 WHERE transaction_id=$2",
+        amount, transaction_id)
+        .execute(tx).await
+}
+
+// a first deposit creates the balance row
+pub async fn credit_balance(tx: &mut DbTransaction<'_>, username: &str, amount: i64) -> Result<PgQueryResult, Error> {
+    sqlx::query!("INSERT INTO lightningchess_balance (username, balance) VALUES ($1, $2) ON CONFLICT (username) DO UPDATE SET balance=lightningchess_balance.balance + EXCLUDED.balance",
+        username, amount)
+        .execute(tx).await
+}
+
+// invoices the stream hasn't seen paid, after the given transaction id
+pub async fn open_transactions(pool: &Pool<Postgres>, after: i32) -> Result<Vec<Transaction>, Error> {
+    sqlx::query_as!(Transaction, "SELECT transaction_id, username, ttype, detail, amount, state, preimage, payment_addr, payment_request, payment_hash, lichess_challenge_id, challenge_id, created_on FROM lightningchess_transaction WHERE state='OPEN' AND transaction_id > $1 ORDER BY transaction_id LIMIT $2",
+        after, PAGE_SIZE)
+        .fetch_all(pool).await
+}
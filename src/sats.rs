@@ -0,0 +1,77 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+// fees are expressed in basis points so no floating point math touches balances
+// 10_000 basis points = 100%
+pub const BASIS_POINTS_DENOMINATOR: i64 = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sats(i64);
+
+impl Sats {
+    pub const ZERO: Sats = Sats(0);
+
+    pub fn new(amount: i64) -> Sats {
+        Sats(amount)
+    }
+
+    pub fn amount(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Sats) -> Option<Sats> {
+        self.0.checked_add(other.0).map(Sats)
+    }
+
+    pub fn checked_sub(self, other: Sats) -> Option<Sats> {
+        self.0.checked_sub(other.0).map(Sats)
+    }
+
+    pub fn checked_mul(self, factor: i64) -> Option<Sats> {
+        self.0.checked_mul(factor).map(Sats)
+    }
+
+    // fee rounded down to the nearest sat. only defined for non negative amounts and rates
+    pub fn checked_fee(self, basis_points: i64) -> Option<Sats> {
+        if self.0 < 0 || basis_points < 0 {
+            return None;
+        }
+        self.0.checked_mul(basis_points).map(|v| Sats(v / BASIS_POINTS_DENOMINATOR))
+    }
+}
+
+impl fmt::Display for Sats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(Sats::new(100).checked_add(Sats::new(5)), Some(Sats::new(105)));
+        assert_eq!(Sats::new(100).checked_sub(Sats::new(5)), Some(Sats::new(95)));
+        assert_eq!(Sats::new(100).checked_mul(2), Some(Sats::new(200)));
+        assert_eq!(Sats::new(i64::MAX).checked_add(Sats::new(1)), None);
+        assert_eq!(Sats::new(i64::MIN).checked_sub(Sats::new(1)), None);
+        assert_eq!(Sats::new(i64::MAX).checked_mul(2), None);
+    }
+
+    #[test]
+    fn checked_fee() {
+        assert_eq!(Sats::new(100).checked_fee(200), Some(Sats::new(2)));
+        assert_eq!(Sats::new(149).checked_fee(200), Some(Sats::new(2)));
+        assert_eq!(Sats::new(150).checked_fee(200), Some(Sats::new(3)));
+        assert_eq!(Sats::new(49).checked_fee(200), Some(Sats::ZERO));
+        assert_eq!(Sats::new(-100).checked_fee(200), None);
+        assert_eq!(Sats::new(100).checked_fee(-200), None);
+        assert_eq!(Sats::new(i64::MAX).checked_fee(200), None);
+    }
+}
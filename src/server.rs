@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use sqlx::{Pool, Postgres};
+use tokio::time::{timeout, Duration};
+use tracing::info;
+use crate::admin_api;
+use crate::config::Config;
+use crate::health::{self, HealthReport};
+use crate::metrics;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
+
+// the ping gives up before the pool's acquire timeout so the health checks still answer while the db is down
+const DB_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn get_metrics() -> String {
+    metrics::render()
+}
+
+async fn ping_db(pool: &Pool<Postgres>) -> bool {
+    matches!(timeout(DB_PING_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await, Ok(Ok(_)))
+}
+
+// what the health endpoints check against
+#[derive(Clone)]
+struct Probes {
+    pool: Pool<Postgres>,
+    db_checks_stale_after: Duration
+}
+
+impl Probes {
+    async fn report(&self) -> HealthReport {
+        health::report(ping_db(&self.pool).await, self.db_checks_stale_after)
+    }
+}
+
+// liveness. the process is up if it can answer, the report is informational
+async fn get_healthz(State(probes): State<Probes>) -> Json<HealthReport> {
+    Json(probes.report().await)
+}
+
+async fn get_readyz(State(probes): State<Probes>) -> (StatusCode, Json<HealthReport>) {
+    let report = probes.report().await;
+    let status = if report.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+fn router(config: Arc<Config>, pool: Pool<Postgres>) -> Result<Router, JobError> {
+    let router = Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .with_state(Probes { pool: pool.clone(), db_checks_stale_after: config.settlement.stale_after() });
+    if config.admin_api.token.is_none() {
+        return Ok(router);
+    }
+    Ok(router.nest("/admin", admin_api::router(config, pool)?))
+}
+
+pub async fn serve_http(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    let addr = config.http.addr;
+    info!(%addr, admin_api = config.admin_api.token.is_some(), "starting http server");
+    axum::Server::bind(&addr).serve(router(config, pool)?.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.requested().await })
+        .await
+        .map_err(|e| JobError::Failed(format!("http server stopped: {}", e)))
+}
@@ -0,0 +1,614 @@
+use serde::Serialize;
+use crate::events::DomainEvent;
+use crate::models::{Challenge, LedgerBalance, LichessExportGameResponse, SettlementError};
+use crate::notifications::NotificationEvent;
+use crate::sats::Sats;
+
+// everything about settling a challenge that doesn't touch the database or lichess.
+// the planners decide what a finished challenge writes and db_checks::apply_plan writes it
+
+const ACCEPTED: &str = "ACCEPTED";
+const WAITING_FOR_ACCEPTANCE: &str = "WAITING FOR ACCEPTANCE";
+
+fn get_winner_username(challenge: &Challenge, winner: &str) -> Result<String, SettlementError> {
+    let color = challenge.color.as_deref().ok_or(SettlementError::MissingColor { challenge_id: challenge.id })?;
+    // determine if user who created the challenge won
+    let creator_won_black = winner == "black" && color == "black";
+    let creator_won_white = winner == "white" && color == "white";
+    Ok(if creator_won_black || creator_won_white {
+        &challenge.username
+    } else {
+        &challenge.opp_username
+    }.to_string())
+}
+
+pub fn challenge_stake(challenge: &Challenge) -> Result<Sats, SettlementError> {
+    match challenge.sats {
+        None => Err(SettlementError::MissingSats { challenge_id: challenge.id }),
+        Some(sats) if sats < 0 => Err(SettlementError::NegativeStake { challenge_id: challenge.id, sats }),
+        Some(sats) => Ok(Sats::new(sats))
+    }
+}
+
+fn calculate_fee_per_person(challenge: &Challenge, fee_basis_points: i64) -> Result<Sats, SettlementError> {
+    challenge_stake(challenge)?
+        .checked_fee(fee_basis_points)
+        .ok_or(SettlementError::Overflow { challenge_id: challenge.id })
+}
+
+// every amount that can be paid out when an accepted challenge finishes
+#[derive(Debug, PartialEq, Eq)]
+pub struct Payouts {
+    pub stake: Sats,
+    pub total_fee: Sats,
+    pub winnings: Sats,
+    pub draw_refund: Sats
+}
+
+pub fn calculate_payouts(challenge: &Challenge, fee_basis_points: i64) -> Result<Payouts, SettlementError> {
+    let overflow = || SettlementError::Overflow { challenge_id: challenge.id };
+    let stake = challenge_stake(challenge)?;
+    let fee_per_person = calculate_fee_per_person(challenge, fee_basis_points)?;
+    let total_fee = fee_per_person.checked_mul(2).ok_or_else(overflow)?;
+    let winnings = stake.checked_add(stake)
+        .and_then(|pot| pot.checked_sub(total_fee))
+        .ok_or_else(overflow)?;
+    let draw_refund = stake.checked_sub(fee_per_person).ok_or_else(overflow)?;
+    Ok(Payouts { stake, total_fee, winnings, draw_refund })
+}
+
+// the fee for a ledger detail, like "2" or "2.5" for 200 or 250 basis points
+pub fn format_fee_percent(fee_basis_points: i64) -> String {
+    let (whole, hundredths) = (fee_basis_points / 100, fee_basis_points % 100);
+    if hundredths == 0 {
+        whole.to_string()
+    } else if hundredths % 10 == 0 {
+        format!("{}.{}", whole, hundredths / 10)
+    } else {
+        format!("{}.{:02}", whole, hundredths)
+    }
+}
+
+// refunds for a challenge that was never accepted. only players with an outstanding debit
+// for this challenge in the ledger get sats back, and only as much as was debited. a challenge
+// with a stake but no ledger rows for either player is left for an operator instead of guessed at
+pub fn calculate_refunds(challenge: &Challenge, ledger: &[LedgerBalance]) -> Result<Vec<(String, Sats)>, SettlementError> {
+    let players = [&challenge.username, &challenge.opp_username];
+    if challenge_stake(challenge)? > Sats::ZERO && !ledger.iter().any(|b| players.contains(&&b.username)) {
+        return Err(SettlementError::MissingStake { challenge_id: challenge.id });
+    }
+    let mut refunds = Vec::new();
+    for username in players {
+        let mut net = Sats::ZERO;
+        for balance in ledger.iter().filter(|b| &b.username == username) {
+            net = net.checked_add(Sats::new(balance.net_amount))
+                .ok_or(SettlementError::Overflow { challenge_id: challenge.id })?;
+        }
+        if net.is_negative() {
+            let refund = Sats::ZERO.checked_sub(net)
+                .ok_or(SettlementError::Overflow { challenge_id: challenge.id })?;
+            refunds.push((username.to_string(), refund));
+        }
+    }
+    Ok(refunds)
+}
+
+// how an accepted challenge ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    // the winner's username
+    Win(String),
+    Draw,
+    // the game never happened. both stakes go back without a fee
+    Unplayed
+}
+
+// None while the game is still going
+pub fn lichess_outcome(challenge: &Challenge, game: &LichessExportGameResponse) -> Result<Option<Outcome>, SettlementError> {
+    if game.status == "created" || game.status == "started" {
+        return Ok(None);
+    }
+    Ok(match game.winner.as_deref() {
+        Some(winner) if winner == "black" || winner == "white" => Some(Outcome::Win(get_winner_username(challenge, winner)?)),
+        _ => Some(Outcome::Draw)
+    })
+}
+
+// why the stakes of a challenge are returned from the ledger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Refund {
+    // never accepted in time
+    Expiry,
+    // cancelled by an operator
+    Void { note: String },
+    // refunded by an operator. an accepted challenge is completed, one that was never accepted is voided
+    Operator { note: String }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LedgerInsert {
+    pub username: String,
+    pub ttype: String,
+    pub amount: Sats,
+    pub state: String,
+    pub detail: String
+}
+
+impl LedgerInsert {
+    // the jobs only write settled rows
+    fn settled(username: &str, ttype: &str, amount: Sats, detail: &str) -> LedgerInsert {
+        LedgerInsert { username: username.to_string(), ttype: ttype.to_string(), amount, state: "SETTLED".to_string(), detail: detail.to_string() }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub username: String,
+    pub amount: Sats
+}
+
+// the status update only applies while the challenge is still in the from status
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusChange {
+    pub from: String,
+    pub to: String
+}
+
+// everything settling one challenge writes, in one transaction
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SettlementPlan {
+    pub challenge_id: i32,
+    pub ledger: Vec<LedgerInsert>,
+    pub balances: Vec<BalanceChange>,
+    pub status: StatusChange,
+    pub notifications: Vec<(String, NotificationEvent)>,
+    pub event: DomainEvent
+}
+
+impl SettlementPlan {
+    fn new(challenge: &Challenge, status: StatusChange, event: DomainEvent) -> SettlementPlan {
+        SettlementPlan { challenge_id: challenge.id, ledger: Vec::new(), balances: Vec::new(), status, notifications: Vec::new(), event }
+    }
+
+    // every ledger row moves the same amount into the user's balance
+    fn credit(&mut self, username: &str, ttype: &str, amount: Sats, detail: &str) {
+        self.ledger.push(LedgerInsert::settled(username, ttype, amount, detail));
+        self.balances.push(BalanceChange { username: username.to_string(), amount });
+    }
+
+    fn notify(&mut self, username: &str, event: NotificationEvent) {
+        self.notifications.push((username.to_string(), event));
+    }
+}
+
+// the plan for an accepted challenge. detail goes on the players' ledger rows, the fee row gets its own
+pub fn plan_settlement(challenge: &Challenge,
+                       outcome: &Outcome,
+                       fee_basis_points: i64,
+                       admin: &str,
+                       detail: &str) -> Result<SettlementPlan, SettlementError> {
+    let payouts = calculate_payouts(challenge, fee_basis_points)?;
+    let lichess_challenge_id = challenge.lichess_challenge_id.clone().unwrap_or_default();
+    let (outcome_name, winner) = match outcome {
+        Outcome::Win(winner) => ("win", Some(winner.clone())),
+        Outcome::Draw => ("draw", None),
+        Outcome::Unplayed => ("unplayed", None)
+    };
+    let status = StatusChange { from: ACCEPTED.to_string(), to: "COMPLETED".to_string() };
+    let event = DomainEvent::ChallengeSettled {
+        challenge_id: challenge.id,
+        lichess_challenge_id: challenge.lichess_challenge_id.clone(),
+        outcome: outcome_name.to_string(),
+        winner
+    };
+    let mut plan = SettlementPlan::new(challenge, status, event);
+
+    if *outcome != Outcome::Unplayed {
+        plan.credit(admin, "fee", payouts.total_fee, &format!("fee from challenge {}", challenge.id));
+    }
+    match outcome {
+        Outcome::Win(winner) => {
+            if winner != &challenge.username && winner != &challenge.opp_username {
+                return Err(SettlementError::NotAPlayer { challenge_id: challenge.id, username: winner.clone() });
+            }
+            let loser = if winner == &challenge.username { &challenge.opp_username } else { &challenge.username };
+            plan.credit(winner, "winnings", payouts.winnings, detail);
+            plan.notify(winner, NotificationEvent::ChallengeSettled {
+                challenge_id: challenge.id,
+                lichess_challenge_id: lichess_challenge_id.clone(),
+                result: "won".to_string(),
+                amount: payouts.winnings
+            });
+            plan.notify(loser, NotificationEvent::ChallengeSettled {
+                challenge_id: challenge.id,
+                lichess_challenge_id,
+                result: "lost".to_string(),
+                amount: Sats::ZERO
+            });
+        }
+        Outcome::Draw => {
+            let draw_event = NotificationEvent::ChallengeSettled {
+                challenge_id: challenge.id,
+                lichess_challenge_id,
+                result: "draw".to_string(),
+                amount: payouts.draw_refund
+            };
+            for username in [&challenge.username, &challenge.opp_username] {
+                plan.credit(username, "draw", payouts.draw_refund, detail);
+                plan.notify(username, draw_event.clone());
+            }
+        }
+        Outcome::Unplayed => {
+            let refunded_event = NotificationEvent::ChallengeRefunded {
+                challenge_id: challenge.id,
+                lichess_challenge_id,
+                amount: payouts.stake
+            };
+            for username in [&challenge.username, &challenge.opp_username] {
+                plan.credit(username, "expired", payouts.stake, detail);
+                plan.notify(username, refunded_event.clone());
+            }
+        }
+    }
+    Ok(plan)
+}
+
+// the plan for returning what the ledger shows each player paid in, without a fee
+pub fn plan_refund(challenge: &Challenge,
+                   refund: &Refund,
+                   ledger: &[LedgerBalance],
+                   detail: &str) -> Result<SettlementPlan, SettlementError> {
+    let refunds = calculate_refunds(challenge, ledger)?;
+    let (ttype, status, event) = match refund {
+        Refund::Expiry => (
+            "expired",
+            StatusChange { from: WAITING_FOR_ACCEPTANCE.to_string(), to: "EXPIRED".to_string() },
+            DomainEvent::ChallengeExpired { challenge_id: challenge.id }
+        ),
+        Refund::Void { note } => (
+            "refund",
+            StatusChange { from: challenge.status.clone().unwrap_or_default(), to: "VOID".to_string() },
+            DomainEvent::ChallengeVoided { challenge_id: challenge.id, note: note.clone() }
+        ),
+        Refund::Operator { note } => {
+            let from = challenge.status.clone().unwrap_or_default();
+            let to = if from == ACCEPTED { "COMPLETED" } else { "VOID" };
+            (
+                "refund",
+                StatusChange { from, to: to.to_string() },
+                DomainEvent::ChallengeRefunded { challenge_id: challenge.id, note: note.clone() }
+            )
+        }
+    };
+    let mut plan = SettlementPlan::new(challenge, status, event);
+    for (username, amount) in refunds {
+        plan.credit(&username, ttype, amount, detail);
+        let notification = match refund {
+            Refund::Expiry => NotificationEvent::ChallengeExpired { challenge_id: challenge.id, amount },
+            Refund::Void { .. } => NotificationEvent::ChallengeVoided { challenge_id: challenge.id, amount },
+            Refund::Operator { .. } => NotificationEvent::ChallengeRefundedBySupport { challenge_id: challenge.id, amount }
+        };
+        plan.notify(&username, notification);
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::sats::BASIS_POINTS_DENOMINATOR;
+    use crate::test_support::test_challenge as get_challenge;
+
+    #[test]
+    fn winner_creator_as_white() {
+        let challenge = get_challenge();
+
+        // creator won as white
+        assert_eq!(get_winner_username(&challenge, "white"), Ok("user1".to_string()));
+        // creator lost as white
+        assert_eq!(get_winner_username(&challenge, "black"), Ok("user2".to_string()));
+    }
+
+    #[test]
+    fn winner_creator_as_black() {
+        let mut challenge = get_challenge();
+        challenge.color = Some("black".to_string());
+
+        // creator lost as black
+        assert_eq!(get_winner_username(&challenge, "white"), Ok("user2".to_string()));
+        // creator won as black
+        assert_eq!(get_winner_username(&challenge, "black"), Ok("user1".to_string()));
+    }
+
+    #[test]
+    fn winner_without_creator_color() {
+        let challenge = Challenge { color: None, ..get_challenge() };
+        assert_eq!(get_winner_username(&challenge, "white"), Err(SettlementError::MissingColor { challenge_id: 1 }));
+    }
+
+    const FEE_BASIS_POINTS: i64 = 200;
+
+    #[test]
+    fn fee_percents() {
+        assert_eq!(format_fee_percent(200), "2");
+        assert_eq!(format_fee_percent(250), "2.5");
+        assert_eq!(format_fee_percent(205), "2.05");
+        assert_eq!(format_fee_percent(5), "0.05");
+        assert_eq!(format_fee_percent(0), "0");
+    }
+
+    #[test]
+    fn calculate_fee_per_person_test() {
+        let mut challenge = get_challenge();
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(2)));
+        challenge.sats = Some(101);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(2)));
+        challenge.sats = Some(110);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(2)));
+        challenge.sats = Some(149);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(2)));
+        challenge.sats = Some(150);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(3)));
+        challenge.sats = Some(199);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(3)));
+        challenge.sats = Some(200);
+        assert_eq!(calculate_fee_per_person(&challenge, FEE_BASIS_POINTS), Ok(Sats::new(4)));
+    }
+
+    #[test]
+    fn calculate_payouts_test() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_payouts(&challenge, FEE_BASIS_POINTS), Ok(Payouts {
+            stake: Sats::new(100),
+            total_fee: Sats::new(4),
+            winnings: Sats::new(196),
+            draw_refund: Sats::new(98)
+        }));
+    }
+
+    #[test]
+    fn calculate_payouts_invalid_sats() {
+        let mut challenge = get_challenge();
+        challenge.sats = None;
+        assert_eq!(calculate_payouts(&challenge, FEE_BASIS_POINTS), Err(SettlementError::MissingSats { challenge_id: 1 }));
+        challenge.sats = Some(-100);
+        assert_eq!(calculate_payouts(&challenge, FEE_BASIS_POINTS), Err(SettlementError::NegativeStake { challenge_id: 1, sats: -100 }));
+        challenge.sats = Some(i64::MAX);
+        assert_eq!(calculate_payouts(&challenge, FEE_BASIS_POINTS), Err(SettlementError::Overflow { challenge_id: 1 }));
+    }
+
+    fn ledger(entries: &[(&str, i64)]) -> Vec<LedgerBalance> {
+        entries.iter()
+            .map(|(username, net_amount)| LedgerBalance { username: username.to_string(), net_amount: *net_amount })
+            .collect()
+    }
+
+    #[test]
+    fn refunds_need_a_stake_in_the_ledger() {
+        // nothing is refunded on the strength of challenge.sats alone
+        let mut challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[])), Err(SettlementError::MissingStake { challenge_id: 1 }));
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user3", -100)])), Err(SettlementError::MissingStake { challenge_id: 1 }));
+        challenge.sats = Some(0);
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[])), Ok(vec![]));
+        challenge.sats = None;
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[])), Err(SettlementError::MissingSats { challenge_id: 1 }));
+    }
+
+    #[test]
+    fn refunds_only_creator_debited() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user1", -100)])),
+                   Ok(vec![("user1".to_string(), Sats::new(100))]));
+    }
+
+    #[test]
+    fn refunds_only_opponent_debited() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user2", -100)])),
+                   Ok(vec![("user2".to_string(), Sats::new(100))]));
+    }
+
+    #[test]
+    fn refunds_both_debited() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user2", -100), ("user1", -100)])),
+                   Ok(vec![("user1".to_string(), Sats::new(100)), ("user2".to_string(), Sats::new(100))]));
+    }
+
+    #[test]
+    fn refunds_refund_what_was_debited() {
+        // the ledger is the source of truth, not challenge.sats
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user1", -40)])),
+                   Ok(vec![("user1".to_string(), Sats::new(40))]));
+    }
+
+    #[test]
+    fn refunds_already_refunded() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user1", 0)])), Ok(vec![]));
+    }
+
+    #[test]
+    fn refunds_ignore_other_users() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user1", 0), ("user3", -100)])), Ok(vec![]));
+    }
+
+    #[test]
+    fn refunds_overflow() {
+        let challenge = get_challenge();
+        assert_eq!(calculate_refunds(&challenge, &ledger(&[("user1", i64::MIN)])),
+                   Err(SettlementError::Overflow { challenge_id: 1 }));
+    }
+
+    fn accepted_challenge() -> Challenge {
+        let mut challenge = get_challenge();
+        challenge.status = Some("ACCEPTED".to_string());
+        challenge.lichess_challenge_id = Some("abc".to_string());
+        challenge
+    }
+
+    fn settled(username: &str, ttype: &str, amount: i64, detail: &str) -> LedgerInsert {
+        LedgerInsert::settled(username, ttype, Sats::new(amount), detail)
+    }
+
+    fn credited(plan: &SettlementPlan) -> Vec<(String, i64)> {
+        plan.balances.iter().map(|change| (change.username.clone(), change.amount.amount())).collect()
+    }
+
+    fn export(status: &str, winner: Option<&str>) -> LichessExportGameResponse {
+        LichessExportGameResponse {
+            id: "abc".to_string(),
+            rated: false,
+            variant: "standard".to_string(),
+            speed: "blitz".to_string(),
+            perf: "blitz".to_string(),
+            status: status.to_string(),
+            winner: winner.map(str::to_string)
+        }
+    }
+
+    #[test]
+    fn lichess_outcomes() {
+        let challenge = accepted_challenge();
+        assert_eq!(lichess_outcome(&challenge, &export("created", None)), Ok(None));
+        assert_eq!(lichess_outcome(&challenge, &export("started", None)), Ok(None));
+        assert_eq!(lichess_outcome(&challenge, &export("mate", Some("white"))), Ok(Some(Outcome::Win("user1".to_string()))));
+        assert_eq!(lichess_outcome(&challenge, &export("resign", Some("black"))), Ok(Some(Outcome::Win("user2".to_string()))));
+        assert_eq!(lichess_outcome(&challenge, &export("stalemate", None)), Ok(Some(Outcome::Draw)));
+        assert_eq!(lichess_outcome(&challenge, &export("aborted", Some(""))), Ok(Some(Outcome::Draw)));
+    }
+
+    #[test]
+    fn plan_win() {
+        let challenge = accepted_challenge();
+        let plan = plan_settlement(&challenge, &Outcome::Win("user2".to_string()), FEE_BASIS_POINTS, "admin", "game").unwrap();
+        assert_eq!(plan.ledger, vec![settled("admin", "fee", 4, "fee from challenge 1"), settled("user2", "winnings", 196, "game")]);
+        assert_eq!(credited(&plan), vec![("admin".to_string(), 4), ("user2".to_string(), 196)]);
+        assert_eq!(plan.status, StatusChange { from: "ACCEPTED".to_string(), to: "COMPLETED".to_string() });
+        assert_eq!(plan.notifications, vec![
+            ("user2".to_string(), NotificationEvent::ChallengeSettled { challenge_id: 1, lichess_challenge_id: "abc".to_string(), result: "won".to_string(), amount: Sats::new(196) }),
+            ("user1".to_string(), NotificationEvent::ChallengeSettled { challenge_id: 1, lichess_challenge_id: "abc".to_string(), result: "lost".to_string(), amount: Sats::ZERO })
+        ]);
+        assert_eq!(plan.event, DomainEvent::ChallengeSettled {
+            challenge_id: 1,
+            lichess_challenge_id: Some("abc".to_string()),
+            outcome: "win".to_string(),
+            winner: Some("user2".to_string())
+        });
+    }
+
+    #[test]
+    fn plan_draw() {
+        let challenge = accepted_challenge();
+        let plan = plan_settlement(&challenge, &Outcome::Draw, FEE_BASIS_POINTS, "admin", "game").unwrap();
+        assert_eq!(plan.ledger, vec![
+            settled("admin", "fee", 4, "fee from challenge 1"),
+            settled("user1", "draw", 98, "game"),
+            settled("user2", "draw", 98, "game")
+        ]);
+        assert_eq!(plan.notifications.len(), 2);
+        assert!(matches!(plan.event, DomainEvent::ChallengeSettled { ref outcome, winner: None, .. } if outcome == "draw"));
+    }
+
+    #[test]
+    fn plan_unplayed() {
+        let challenge = accepted_challenge();
+        let plan = plan_settlement(&challenge, &Outcome::Unplayed, FEE_BASIS_POINTS, "admin", "never played").unwrap();
+        // no fee when the game never happened
+        assert_eq!(plan.ledger, vec![settled("user1", "expired", 100, "never played"), settled("user2", "expired", 100, "never played")]);
+        assert_eq!(plan.notifications[0], ("user1".to_string(), NotificationEvent::ChallengeRefunded { challenge_id: 1, lichess_challenge_id: "abc".to_string(), amount: Sats::new(100) }));
+        assert_eq!(plan.status.to, "COMPLETED");
+    }
+
+    #[test]
+    fn plan_rejects_bad_challenges() {
+        let mut challenge = accepted_challenge();
+        assert_eq!(plan_settlement(&challenge, &Outcome::Win("user3".to_string()), FEE_BASIS_POINTS, "admin", "game"),
+                   Err(SettlementError::NotAPlayer { challenge_id: 1, username: "user3".to_string() }));
+        challenge.sats = None;
+        assert_eq!(plan_settlement(&challenge, &Outcome::Draw, FEE_BASIS_POINTS, "admin", "game"),
+                   Err(SettlementError::MissingSats { challenge_id: 1 }));
+    }
+
+    #[test]
+    fn plan_expiry() {
+        let mut challenge = get_challenge();
+        challenge.status = Some("WAITING FOR ACCEPTANCE".to_string());
+        let plan = plan_refund(&challenge, &Refund::Expiry, &ledger(&[("user1", -100)]), "expired").unwrap();
+        assert_eq!(plan.ledger, vec![settled("user1", "expired", 100, "expired")]);
+        assert_eq!(plan.notifications, vec![("user1".to_string(), NotificationEvent::ChallengeExpired { challenge_id: 1, amount: Sats::new(100) })]);
+        assert_eq!(plan.status, StatusChange { from: "WAITING FOR ACCEPTANCE".to_string(), to: "EXPIRED".to_string() });
+        assert_eq!(plan.event, DomainEvent::ChallengeExpired { challenge_id: 1 });
+    }
+
+    #[test]
+    fn plan_void() {
+        let challenge = accepted_challenge();
+        let refund = Refund::Void { note: "cheating".to_string() };
+        let plan = plan_refund(&challenge, &refund, &ledger(&[("user1", -100), ("user2", -100)]), "voided").unwrap();
+        assert_eq!(plan.ledger, vec![settled("user1", "refund", 100, "voided"), settled("user2", "refund", 100, "voided")]);
+        assert_eq!(plan.status, StatusChange { from: "ACCEPTED".to_string(), to: "VOID".to_string() });
+        assert_eq!(plan.event, DomainEvent::ChallengeVoided { challenge_id: 1, note: "cheating".to_string() });
+    }
+
+    #[test]
+    fn plan_operator_refund() {
+        let challenge = accepted_challenge();
+        let refund = Refund::Operator { note: "lichess outage".to_string() };
+        // only what the ledger shows was staked comes back
+        let plan = plan_refund(&challenge, &refund, &ledger(&[("user1", -100)]), "refunded").unwrap();
+        assert_eq!(plan.ledger, vec![settled("user1", "refund", 100, "refunded")]);
+        assert_eq!(plan.notifications, vec![("user1".to_string(), NotificationEvent::ChallengeRefundedBySupport { challenge_id: 1, amount: Sats::new(100) })]);
+        assert_eq!(plan.status, StatusChange { from: "ACCEPTED".to_string(), to: "COMPLETED".to_string() });
+        assert_eq!(plan.event, DomainEvent::ChallengeRefunded { challenge_id: 1, note: "lichess outage".to_string() });
+
+        let waiting = Challenge { status: Some("WAITING FOR ACCEPTANCE".to_string()), ..get_challenge() };
+        let plan = plan_refund(&waiting, &refund, &ledger(&[("user1", -100)]), "refunded").unwrap();
+        assert_eq!(plan.status, StatusChange { from: "WAITING FOR ACCEPTANCE".to_string(), to: "VOID".to_string() });
+    }
+
+    fn total(amounts: impl Iterator<Item = Sats>) -> i128 {
+        amounts.map(|amount| amount.amount() as i128).sum()
+    }
+
+    fn outcome_strategy() -> impl Strategy<Value = Outcome> {
+        prop_oneof![
+            Just(Outcome::Win("user1".to_string())),
+            Just(Outcome::Win("user2".to_string())),
+            Just(Outcome::Draw),
+            Just(Outcome::Unplayed)
+        ]
+    }
+
+    proptest! {
+        // both stakes are paid out in full between the players and the fee, and the balances
+        // move by exactly what the ledger records. larger stakes overflow the fee calculation
+        #[test]
+        fn settlement_conserves_sats(sats in 0..=i64::MAX / BASIS_POINTS_DENOMINATOR, fee_basis_points in 0..=BASIS_POINTS_DENOMINATOR, outcome in outcome_strategy()) {
+            let mut challenge = accepted_challenge();
+            challenge.sats = Some(sats);
+            let plan = plan_settlement(&challenge, &outcome, fee_basis_points, "admin", "game").unwrap();
+            prop_assert_eq!(total(plan.ledger.iter().map(|insert| insert.amount)), 2 * sats as i128);
+            prop_assert!(plan.ledger.iter().all(|insert| !insert.amount.is_negative()));
+            let ledger: Vec<(String, Sats)> = plan.ledger.iter().map(|insert| (insert.username.clone(), insert.amount)).collect();
+            let balances: Vec<(String, Sats)> = plan.balances.iter().map(|change| (change.username.clone(), change.amount)).collect();
+            prop_assert_eq!(ledger, balances);
+        }
+
+        // a refund returns exactly what the players are owed by the ledger, never more
+        #[test]
+        fn refunds_conserve_sats(user1 in -1_000_000_000i64..1_000_000_000, user2 in -1_000_000_000i64..1_000_000_000, other in -1_000_000_000i64..0) {
+            let challenge = get_challenge();
+            let balances = ledger(&[("user1", user1), ("user2", user2), ("user3", other)]);
+            let plan = plan_refund(&challenge, &Refund::Expiry, &balances, "expired").unwrap();
+            let owed = -(user1.min(0) as i128) - (user2.min(0) as i128);
+            prop_assert_eq!(total(plan.ledger.iter().map(|insert| insert.amount)), owed);
+            prop_assert_eq!(total(plan.balances.iter().map(|change| change.amount)), owed);
+            prop_assert!(plan.ledger.iter().all(|insert| insert.amount.amount() > 0 && insert.username != "user3"));
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+// handed to every job. jobs finish the challenge or invoice they are on, stop taking new work and return
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>
+}
+
+impl Shutdown {
+    pub fn new() -> (watch::Sender<bool>, Shutdown) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Shutdown { receiver })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    // resolves once shutdown is requested, or straight away if it already was
+    pub async fn requested(&self) {
+        let mut receiver = self.receiver.clone();
+        // an error means the supervisor is gone, which is a shutdown too
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+}
+
+// resolves on the first SIGTERM or SIGINT
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM"),
+        _ = interrupt.recv() => info!("received SIGINT")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requested_after_send() {
+        let (sender, shutdown) = Shutdown::new();
+        assert!(!shutdown.is_requested());
+        sender.send(true).unwrap();
+        assert!(shutdown.is_requested());
+        shutdown.requested().await;
+    }
+}
@@ -1,106 +1,247 @@
-use std::env;
 use std::str::from_utf8;
+use std::sync::Arc;
 use reqwest::Client;
+use tokio::time::{sleep, Duration};
 use sqlx::{Pool, Postgres};
-use sqlx::postgres::PgPoolOptions;
-use crate::models::{Invoice, InvoiceResult, LightningChessResult, Transaction};
+use tracing::{debug, error, field, info, info_span, instrument, warn, Instrument, Span};
+use crate::{health, metrics};
+use crate::config::Config;
+use crate::control::{CONTROL, SUBSCRIBE_INVOICES};
+use crate::error::{LightningChessError, LightningChessResult};
+use crate::models::{Invoice, InvoiceResult};
+use crate::events::{record_event, DomainEvent};
+use crate::notifications::{NotificationEvent, Notifier};
+use crate::repository::{credit_balance, lock_deposit, settle_deposit};
+use crate::sats::Sats;
+use crate::shutdown::Shutdown;
+use crate::supervisor::{JobError, JobResult};
 
-pub async fn update_settled_invoice(pool: &Pool<Postgres>, invoice: &Invoice) -> LightningChessResult<bool> {
+// a deposit that hits a retryable error is tried again before the stream moves on, since lnd only
+// sends it again when the subscription reconnects
+const INVOICE_UPDATE_ATTEMPTS: u32 = 3;
+const INVOICE_UPDATE_BACKOFF: Duration = Duration::from_secs(1);
+
+#[instrument(skip_all, fields(payment_addr = %invoice.payment_addr, username = field::Empty))]
+pub async fn update_settled_invoice(pool: &Pool<Postgres>, notifier: &Notifier, invoice: &Invoice) -> LightningChessResult<bool> {
     let mut tx = pool.begin().await?;
-    println!("created tx");
+    debug!("created tx");
 
     // look up in database
-    let transaction = sqlx::query_as::<_,Transaction>( "SELECT * FROM lightningchess_transaction WHERE payment_addr=$1 FOR UPDATE")
-        .bind(&invoice.payment_addr)
-        .fetch_one(&mut tx).await?;
-    println!("transaction: {}", serde_json::to_string(&transaction).unwrap());
+    let transaction = lock_deposit(&mut tx, &invoice.payment_addr).await?;
+    // the transaction row holds the preimage so only log what we need
+    Span::current().record("username", transaction.username.as_str());
+    debug!(transaction_id = transaction.transaction_id, state = %transaction.state, "found transaction");
+
+    // lnd replays settled invoices when the subscription reconnects. only credit a deposit once
+    if transaction.state == "SETTLED" {
+        debug!("deposit already settled");
+        return Ok(false);
+    }
 
     // update transaction table
-    let amount = invoice.amt_paid_sat.parse::<i64>().unwrap();
-    sqlx::query( "UPDATE lightningchess_transaction SET state='SETTLED', amount=$1 WHERE transaction_id=$2")
-        .bind(&amount)
-        .bind(&transaction.transaction_id)
-        .execute(&mut tx).await?;
-    println!("updated transaction");
+    let amount = invoice.amt_paid_sat.parse::<i64>()
+        .map_err(|e| LightningChessError::Parse(format!("invalid amt_paid_sat {}: {}", invoice.amt_paid_sat, e)))?;
+    settle_deposit(&mut tx, transaction.transaction_id, amount).await?;
+    debug!("updated transaction");
 
     // update balance table
-    sqlx::query( "INSERT INTO lightningchess_balance (username, balance) VALUES ($1, $2) ON CONFLICT (username) DO UPDATE SET balance=lightningchess_balance.balance + $3 WHERE lightningchess_balance.username=$4")
-        .bind(&transaction.username)
-        .bind(&amount)
-        .bind(&amount)
-        .bind(&transaction.username)
-        .execute(&mut tx).await?;
-    println!("updated balance");
+    credit_balance(&mut tx, &transaction.username, amount).await?;
+    debug!("updated balance");
+
+    let deposit_event = NotificationEvent::DepositCredited { amount: Sats::new(amount) };
+    notifier.enqueue(&mut tx, &transaction.username, &deposit_event).await?;
+
+    let settled_event = DomainEvent::DepositSettled {
+        transaction_id: transaction.transaction_id,
+        username: transaction.username.to_string(),
+        amount: Sats::new(amount),
+        payment_addr: transaction.payment_addr.clone()
+    };
+    record_event(&mut tx, &settled_event).await?;
 
     // commit
     tx.commit().await?;
-    println!("committed");
+    info!(amount, "deposit settled");
+    metrics::INVOICES_SETTLED.inc();
     Ok(true)
 }
 
-pub async fn subscribe_invoices() {
-    let db_url = env::var("DB_URL").unwrap();
+async fn settle_invoice(pool: &Pool<Postgres>, notifier: &Notifier, invoice: &Invoice) {
+    let mut backoff = INVOICE_UPDATE_BACKOFF;
+    for attempt in 1..=INVOICE_UPDATE_ATTEMPTS {
+        let e = match update_settled_invoice(pool, notifier, invoice).await {
+            Ok(_) => return,
+            Err(e) => e.for_invoice(&invoice.payment_addr)
+        };
+        if !e.is_retryable() || attempt == INVOICE_UPDATE_ATTEMPTS {
+            error!(error = %e, attempt, "error updating settled invoice");
+            return;
+        }
+        warn!(error = %e, attempt, ?backoff, "error updating settled invoice. retrying");
+        sleep(backoff).await;
+        backoff *= 2;
+    }
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(1)
-        .connect(&db_url)
-        .await.unwrap();
+// one line of the invoice stream. it holds the preimage, so the error only says where it went wrong
+fn parse_invoice(line: &[u8]) -> LightningChessResult<InvoiceResult> {
+    let line = from_utf8(line)
+        .map_err(|e| LightningChessError::Parse(format!("invoice isn't utf-8 after byte {}", e.valid_up_to())))?;
+    serde_json::from_str(line)
+        .map_err(|e| LightningChessError::Parse(format!("unreadable invoice: {:?} error at column {}", e.classify(), e.column())))
+}
 
+pub async fn subscribe_invoices(config: Arc<Config>, pool: Pool<Postgres>, shutdown: Shutdown) -> JobResult {
+    // no overall timeout since the stream stays open
+    let client = Client::builder()
+        .connect_timeout(config.lnd.request_timeout())
+        .build()
+        .map_err(|e| JobError::Fatal(format!("can't build lnd client: {}", e)))?;
+
+    let notifier = Notifier::from_config(&config)?;
+    let mut connection_count = 1;
     loop {
-        println!("Starting to subscribe to invoices!");
-        let macaroon = env::var("LND_MACAROON").unwrap();
-
-        let response = Client::new()
-            .get(format!("https://lightningchess.m.voltageapp.io:8080/v1/invoices/subscribe"))
-            .header("Grpc-Metadata-macaroon", macaroon)
-            .send().await;
-
-        match response {
-            Ok(mut res) => {
-                let mut still_chunky = true;
-                let mut invoice_str = "".to_owned();
-                while still_chunky {
-                    let res_bytes = res.chunk().await;
-                    match res_bytes {
-                        Ok(maybe_bytes) => {
-                            match maybe_bytes {
-                                Some(bytes) => {
-                                    println!("bytes: {:?}", bytes);
-                                    let chunk = from_utf8(&bytes).unwrap();
-                                    println!("chunk: {}", chunk);
-                                    invoice_str.push_str(chunk);
-                                    println!("invoice str = {}", &invoice_str);
-                                    if chunk.ends_with("\n") {
-                                        println!("End of invoice");
-                                        let invoice_result: InvoiceResult = serde_json::from_str(&invoice_str).unwrap();
-                                        // if result is settled update db
-                                        if invoice_result.result.state == "SETTLED" {
-                                            let db_update_result = update_settled_invoice(&pool, &invoice_result.result).await;
-                                            match db_update_result {
-                                                Ok(_) => (),
-                                                Err(e) => println!("Error db update result {}", e)
+        CONTROL.wait_while_paused(SUBSCRIBE_INVOICES, &shutdown).await;
+        if shutdown.is_requested() {
+            break;
+        }
+        async {
+            info!("subscribing to invoices");
+            let request_timer = metrics::EXTERNAL_REQUEST_DURATION.with_label_values(&["lnd", "invoices_subscribe"]).start_timer();
+            let response = client
+                .get(format!("{}/v1/invoices/subscribe", config.lnd.url))
+                .header("Grpc-Metadata-macaroon", config.lnd.macaroon.expose())
+                .send().await;
+            request_timer.observe_duration();
+            metrics::record_request_result("lnd", "invoices_subscribe", &response);
+
+            match response {
+                Ok(mut res) => {
+                    metrics::LND_SUBSCRIPTION_CONNECTED.set(1);
+                    health::record_lnd_subscription(true);
+                    let mut still_chunky = true;
+                    let mut invoice_bytes = Vec::new();
+                    while still_chunky {
+                        // an invoice already read is finished before shutdown is noticed
+                        let res_bytes = tokio::select! {
+                            res_bytes = res.chunk() => res_bytes,
+                            _ = shutdown.requested() => {
+                                info!("closing invoice stream for shutdown");
+                                break;
+                            }
+                            _ = CONTROL.paused(SUBSCRIBE_INVOICES) => {
+                                info!("closing invoice stream while paused");
+                                break;
+                            }
+                        };
+                        match res_bytes {
+                            Ok(maybe_bytes) => {
+                                match maybe_bytes {
+                                    Some(bytes) => {
+                                        // chunks hold the full invoice including r_preimage so they are never logged
+                                        invoice_bytes.extend_from_slice(&bytes);
+                                        if invoice_bytes.ends_with(b"\n") {
+                                            match parse_invoice(&invoice_bytes) {
+                                                Ok(invoice_result) => {
+                                                    debug!(payment_addr = %invoice_result.result.payment_addr, state = %invoice_result.result.state, "received invoice");
+                                                    // if result is settled update db
+                                                    if invoice_result.result.state == "SETTLED" {
+                                                        settle_invoice(&pool, &notifier, &invoice_result.result).await;
+                                                    }
+                                                }
+                                                Err(e) => error!(error = %e, "skipping invoice")
                                             }
-                                        }
 
-                                        // after done reset chunky str
-                                        invoice_str = "".to_string()
+                                            // after done reset chunky bytes
+                                            invoice_bytes.clear();
+                                        }
+                                    },
+                                    None => {
+                                        warn!("invoice stream ended");
+                                        still_chunky = false;
                                     }
-                                },
-                                None => {
-                                    println!("No chonks");
-                                    still_chunky = false;
                                 }
                             }
-                        }
-                        Err(e) => {
-                            println!("error chonking {}", e);
+                            Err(e) => {
+                                error!(error = %e, "error reading invoice stream");
+                            }
                         }
                     }
+                    metrics::LND_SUBSCRIPTION_CONNECTED.set(0);
+                    health::record_lnd_subscription(false);
+                },
+                Err(e) => {
+                    error!(error = %e, "error in v1/invoices/subscribe");
                 }
-            },
-            Err(e) => {
-                println!("error in v1/invoices/subscribe :\n{}", e);
             }
-        }
+        }.instrument(info_span!("invoice_subscription", connection_count)).await;
+        connection_count += 1;
+    }
+    info!("invoice subscription stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{sleep, timeout, Duration};
+    use crate::test_support::{lnd_stub, test_db};
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn credits_settled_deposits() {
+        let db = test_db().await;
+        let (transaction_id,) = sqlx::query_as::<_, (i32,)>("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_addr) VALUES ('carol', 'deposit', 'deposit', 0, 'OPEN', 'addr') RETURNING transaction_id")
+            .fetch_one(&db.pool).await.unwrap();
+        let lnd_url = lnd_stub("addr", 500);
+
+        let (trigger, shutdown) = Shutdown::new();
+        let job = tokio::spawn(subscribe_invoices(Arc::new(db.config("", &lnd_url)), db.pool.clone(), shutdown));
+        let settled = timeout(Duration::from_secs(10), async {
+            loop {
+                let (state, amount) = sqlx::query_as::<_, (String, i64)>("SELECT state, amount FROM lightningchess_transaction WHERE transaction_id=$1")
+                    .bind(transaction_id)
+                    .fetch_one(&db.pool).await.unwrap();
+                if state == "SETTLED" {
+                    return amount;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        }).await.unwrap();
+        trigger.send(true).unwrap();
+        job.await.unwrap().unwrap();
+
+        assert_eq!(settled, 500);
+        assert_eq!(db.balance("carol").await, 500);
+        let (event_type,) = sqlx::query_as::<_, (String,)>("SELECT event_type FROM lightningchess_event_outbox")
+            .fetch_one(&db.pool).await.unwrap();
+        assert_eq!(event_type, "DepositSettled");
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn credits_a_replayed_invoice_once() {
+        let db = test_db().await;
+        sqlx::query("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, payment_addr) VALUES ('carol', 'deposit', 'deposit', 0, 'OPEN', 'addr')")
+            .execute(&db.pool).await.unwrap();
+        let notifier = Notifier::from_config(&db.config("", "")).unwrap();
+        let invoice: Invoice = serde_json::from_value(serde_json::json!({
+            "memo": "", "value": "500", "settled": true, "creation_date": "0", "settle_date": "0",
+            "payment_request": "", "payment_addr": "addr", "expiry": "3600", "amt_paid_sat": "500", "state": "SETTLED"
+        })).unwrap();
+
+        assert!(update_settled_invoice(&db.pool, &notifier, &invoice).await.unwrap());
+        assert!(!update_settled_invoice(&db.pool, &notifier, &invoice).await.unwrap());
+        assert_eq!(db.balance("carol").await, 500);
+        db.drop().await;
+    }
+
+    #[test]
+    fn unreadable_invoices_dont_echo_the_stream() {
+        let error = parse_invoice(b"{\"result\": {\"r_preimage\": \"secret\"\n").err().unwrap();
+        assert!(!error.to_string().contains("secret"));
+        assert!(!error.is_retryable());
+        assert!(matches!(parse_invoice(b"\xff\n").err(), Some(LightningChessError::Parse(_))));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use futures::FutureExt;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+use crate::metrics;
+use crate::shutdown::Shutdown;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// a job that ran this long before failing starts its backoff over
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(300);
+
+// how a job stopped. Failed is restarted, Fatal stops every job
+#[derive(Debug)]
+pub enum JobError {
+    Failed(String),
+    Fatal(String)
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Failed(message) => write!(f, "failed: {}", message),
+            JobError::Fatal(message) => write!(f, "fatal: {}", message)
+        }
+    }
+}
+
+impl From<sqlx::Error> for JobError {
+    fn from(e: sqlx::Error) -> Self {
+        JobError::Failed(e.to_string())
+    }
+}
+
+pub type JobResult = Result<(), JobError>;
+
+type JobFuture = Pin<Box<dyn Future<Output = JobResult> + Send>>;
+
+struct Job {
+    name: &'static str,
+    run: Box<dyn Fn(Shutdown) -> JobFuture + Send + Sync>
+}
+
+#[derive(Default)]
+pub struct Supervisor {
+    jobs: Vec<Job>
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+// runs one job until it finishes or fails fatally, restarting it on errors and panics until shutdown
+async fn supervise(job: Job, shutdown: Shutdown) -> Result<(), String> {
+    let mut restarts: u64 = 0;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        info!(restarts, "starting job");
+        let started = Instant::now();
+        let result = AssertUnwindSafe((job.run)(shutdown.clone())).catch_unwind().await;
+        if started.elapsed() >= BACKOFF_RESET_AFTER {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        match result {
+            Ok(Ok(())) => {
+                info!("job finished");
+                return Ok(());
+            }
+            Ok(Err(JobError::Fatal(message))) => {
+                error!(%message, "job failed fatally");
+                return Err(format!("{}: {}", job.name, message));
+            }
+            Ok(Err(JobError::Failed(message))) => warn!(%message, ?backoff, "job failed. restarting"),
+            Err(_) => error!(?backoff, "job panicked. restarting")
+        }
+
+        if shutdown.is_requested() {
+            info!("not restarting job during shutdown");
+            return Ok(());
+        }
+        restarts += 1;
+        metrics::JOB_RESTARTS.with_label_values(&[job.name]).inc();
+        tokio::select! {
+            _ = sleep(backoff) => (),
+            _ = shutdown.requested() => return Ok(())
+        }
+        backoff = next_backoff(backoff);
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor { jobs: Vec::new() }
+    }
+
+    pub fn add<F, Fut>(mut self, name: &'static str, run: F) -> Supervisor
+        where F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = JobResult> + Send + 'static {
+        self.jobs.push(Job { name, run: Box::new(move |shutdown| Box::pin(run(shutdown))) });
+        self
+    }
+
+    // returns once every job has finished, or after stopping them all when signal resolves or a job fails fatally.
+    // jobs get until deadline to wrap up before they are aborted
+    pub async fn run(self, signal: impl Future<Output = ()>, deadline: Duration) -> Result<(), String> {
+        let (trigger, shutdown) = Shutdown::new();
+        let mut tasks = JoinSet::new();
+        for job in self.jobs {
+            let span = info_span!("job", name = job.name);
+            tasks.spawn(supervise(job, shutdown.clone()).instrument(span));
+        }
+
+        tokio::pin!(signal);
+        let result = loop {
+            tokio::select! {
+                result = tasks.join_next() => match result {
+                    None => return Ok(()),
+                    Some(Ok(Ok(()))) => continue,
+                    Some(Ok(Err(message))) => break Err(message),
+                    Some(Err(e)) => break Err(format!("supervisor task failed: {}", e))
+                },
+                _ = &mut signal => break Ok(())
+            }
+        };
+
+        match &result {
+            Ok(()) => info!(?deadline, "shutting down all jobs"),
+            Err(fatal) => error!(%fatal, ?deadline, "shutting down all jobs")
+        }
+        let _ = trigger.send(true);
+        let stopped = timeout(deadline, async {
+            while let Some(stopped) = tasks.join_next().await {
+                match stopped {
+                    Ok(Ok(())) => (),
+                    Ok(Err(message)) => error!(%message, "job failed during shutdown"),
+                    Err(e) => error!(error = %e, "supervisor task failed during shutdown")
+                }
+            }
+        }).await;
+        match stopped {
+            Ok(()) => info!("all jobs stopped"),
+            Err(_) => {
+                warn!(num_jobs = tasks.len(), "jobs still running after the shutdown deadline. aborting them");
+                tasks.shutdown().await;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::future::pending;
+
+    const DEADLINE: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(next_backoff(Duration::from_secs(1)), Duration::from_secs(2));
+        assert_eq!(next_backoff(Duration::from_secs(32)), Duration::from_secs(60));
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_after_panic_and_error() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let job_runs = runs.clone();
+        let result = Supervisor::new()
+            .add("flaky", move |_| {
+                let runs = job_runs.clone();
+                async move {
+                    match runs.fetch_add(1, Ordering::SeqCst) {
+                        0 => panic!("boom"),
+                        1 => Err(JobError::Failed("try again".to_string())),
+                        _ => Ok(())
+                    }
+                }
+            })
+            .run(pending(), DEADLINE).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fatal_error_stops_other_jobs() {
+        let result = Supervisor::new()
+            .add("forever", |_| async {
+                loop {
+                    sleep(Duration::from_secs(1)).await;
+                }
+            })
+            .add("broken", |_| async { Err(JobError::Fatal("missing config".to_string())) })
+            .run(pending(), DEADLINE).await;
+        assert_eq!(result, Err("broken: missing config".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn signal_lets_jobs_finish_their_work() {
+        let finished = Arc::new(AtomicU32::new(0));
+        let job_finished = finished.clone();
+        let started = Instant::now();
+        let result = Supervisor::new()
+            .add("worker", move |shutdown: Shutdown| {
+                let finished = job_finished.clone();
+                async move {
+                    shutdown.requested().await;
+                    // the work in flight when the signal came
+                    sleep(Duration::from_secs(2)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .run(sleep(Duration::from_secs(5)), DEADLINE).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert_eq!(started.elapsed(), Duration::from_secs(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jobs_aborted_after_deadline() {
+        let started = Instant::now();
+        let result = Supervisor::new()
+            .add("stuck", |_| async {
+                loop {
+                    sleep(Duration::from_secs(1)).await;
+                }
+            })
+            .run(sleep(Duration::from_secs(5)), DEADLINE).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(started.elapsed(), Duration::from_secs(5) + DEADLINE);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use axum::{Json, Router};
+use axum::body::{Bytes, StreamBody};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+use sqlx::{Connection, Executor, PgConnection, Pool, Postgres};
+use sqlx::postgres::PgPoolOptions;
+use crate::config::Config;
+use crate::migrate::MIGRATOR;
+use crate::models::Challenge;
+
+// database tests run against the postgres in TEST_DATABASE_URL. they're marked #[ignore] so a plain
+// cargo test lists them as ignored instead of passing them unrun. run them with
+// `TEST_DATABASE_URL=... cargo test -- --include-ignored`. each test gets its own schema with the
+// migrations applied, so tests can run in parallel and the jobs connect to it through their usual config
+
+const DATABASE_URL_VAR: &str = "TEST_DATABASE_URL";
+
+static SCHEMA_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestDb {
+    pub pool: Pool<Postgres>,
+    pub url: String,
+    base_url: String,
+    schema: String,
+    count: usize
+}
+
+// fails the test when there is no database to test against
+pub async fn test_db() -> TestDb {
+    let db = empty_test_db().await;
+    MIGRATOR.run(&db.pool).await.unwrap();
+    // challenge ids key the in-memory polling state, so keep them apart between tests
+    db.pool.execute(format!("ALTER SEQUENCE challenge_id_seq RESTART WITH {}", db.count * 1000 + 1).as_str()).await.unwrap();
+    db
+}
+
+// a schema without the migrations, for testing them against an existing database
+pub async fn empty_test_db() -> TestDb {
+    let base_url = env::var(DATABASE_URL_VAR)
+        .unwrap_or_else(|_| panic!("database tests need {}", DATABASE_URL_VAR));
+    let count = SCHEMA_COUNT.fetch_add(1, Ordering::SeqCst);
+    let schema = format!("test_{}_{}", std::process::id(), count);
+    let mut conn = PgConnection::connect(&base_url).await.unwrap();
+    conn.execute(format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema).as_str()).await.unwrap();
+    conn.close().await.unwrap();
+
+    let separator = if base_url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}options[search_path]={}", base_url, separator, schema);
+    let pool = PgPoolOptions::new().max_connections(2).connect(&url).await.unwrap();
+    TestDb { pool, url, base_url, schema, count }
+}
+
+impl TestDb {
+    pub fn config(&self, lichess_url: &str, lnd_url: &str) -> Config {
+        Config::for_tests(&self.url, lichess_url, lnd_url)
+    }
+
+    pub async fn balance(&self, username: &str) -> i64 {
+        sqlx::query_as::<_, (i64,)>("SELECT balance FROM lightningchess_balance WHERE username=$1")
+            .bind(username)
+            .fetch_optional(&self.pool).await.unwrap()
+            .map_or(0, |(balance,)| balance)
+    }
+
+    pub async fn set_balance(&self, username: &str, balance: i64) {
+        sqlx::query("INSERT INTO lightningchess_balance (username, balance) VALUES ($1, $2) ON CONFLICT (username) DO UPDATE SET balance=$2")
+            .bind(username)
+            .bind(balance)
+            .execute(&self.pool).await.unwrap();
+    }
+
+    pub async fn status(&self, challenge_id: i32) -> String {
+        sqlx::query_as::<_, (String,)>("SELECT status FROM challenge WHERE id=$1")
+            .bind(challenge_id)
+            .fetch_one(&self.pool).await.unwrap().0
+    }
+
+    // (username, ttype, amount) for every ledger row of a challenge, oldest first
+    pub async fn ledger(&self, challenge_id: i32) -> Vec<(String, String, i64)> {
+        sqlx::query_as("SELECT username, ttype, amount FROM lightningchess_transaction WHERE challenge_id=$1 ORDER BY transaction_id")
+            .bind(challenge_id)
+            .fetch_all(&self.pool).await.unwrap()
+    }
+
+    // a challenge between alice (the creator) and bob for 100 sats each. both stakes are debited
+    // once the challenge is accepted, only alice's while it is waiting
+    pub async fn challenge(&self, status: &str, color: &str, lichess_challenge_id: &str, age_seconds: i32) -> i32 {
+        let (challenge_id,) = sqlx::query_as::<_, (i32,)>("INSERT INTO challenge (username, opp_username, status, sats, color, lichess_challenge_id, created_on, expire_after) VALUES ('alice', 'bob', $1, 100, $2, $3, (now() AT TIME ZONE 'utc') - $4 * interval '1 second', 60) RETURNING id")
+            .bind(status)
+            .bind(color)
+            .bind(lichess_challenge_id)
+            .bind(age_seconds)
+            .fetch_one(&self.pool).await.unwrap();
+        let players: &[&str] = if status == "ACCEPTED" { &["alice", "bob"] } else { &["alice"] };
+        for username in players {
+            sqlx::query("INSERT INTO lightningchess_transaction (username, ttype, detail, amount, state, lichess_challenge_id, challenge_id) VALUES ($1, 'challenge', 'stake', -100, 'SETTLED', $2, $3)")
+                .bind(username)
+                .bind(lichess_challenge_id)
+                .bind(challenge_id)
+                .execute(&self.pool).await.unwrap();
+        }
+        challenge_id
+    }
+
+    pub async fn drop(self) {
+        self.pool.close().await;
+        let mut conn = PgConnection::connect(&self.base_url).await.unwrap();
+        conn.execute(format!("DROP SCHEMA {} CASCADE", self.schema).as_str()).await.unwrap();
+    }
+}
+
+// user1's challenge to user2 for 100 sats each, with user1 playing white. for tests that don't
+// touch the database
+pub fn test_challenge() -> Challenge {
+    Challenge { id: 1,
+        username: "user1".to_string(),
+        time_limit: None,
+        opponent_time_limit: None,
+        increment: None,
+        color: Some("white".to_string()),
+        sats: Some(100),
+        opp_username: "user2".to_string(),
+        status: None,
+        lichess_challenge_id: None,
+        created_on: None,
+        expire_after: None
+    }
+}
+
+// serves the router on a free local port and returns its url
+pub fn serve(router: Router) -> String {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener).unwrap().serve(router.into_make_service());
+    tokio::spawn(server);
+    url
+}
+
+// lichess game export for the given games by id. any other game is a 404
+pub fn lichess_stub(games: &[(&str, &str, Option<&str>)]) -> String {
+    let games: HashMap<String, Value> = games.iter()
+        .map(|(id, status, winner)| (id.to_string(), json!({
+            "id": id,
+            "rated": false,
+            "variant": "standard",
+            "speed": "blitz",
+            "perf": "blitz",
+            "status": status,
+            "winner": winner
+        })))
+        .collect();
+    async fn export(State(games): State<Arc<HashMap<String, Value>>>, Path(id): Path<String>) -> Response {
+        match games.get(&id) {
+            Some(game) => Json(game.clone()).into_response(),
+            None => StatusCode::NOT_FOUND.into_response()
+        }
+    }
+    serve(Router::new().route("/game/export/:id", get(export)).with_state(Arc::new(games)))
+}
+
+// an lnd invoice stream that sends one settled invoice on the first subscription, then stays
+// open without sending anything so the job doesn't reconnect and see it again
+pub fn lnd_stub(payment_addr: &str, amt_paid_sat: i64) -> String {
+    let invoice = json!({ "result": {
+        "memo": "",
+        "value": amt_paid_sat.to_string(),
+        "settled": true,
+        "creation_date": "0",
+        "settle_date": "0",
+        "payment_request": "lnbc",
+        "payment_addr": payment_addr,
+        "expiry": "3600",
+        "amt_paid_sat": amt_paid_sat.to_string(),
+        "state": "SETTLED"
+    }});
+    let pending = Arc::new(Mutex::new(Some(format!("{}\n", invoice))));
+    async fn subscribe(State(pending): State<Arc<Mutex<Option<String>>>>) -> Response {
+        let first = pending.lock().unwrap().take().map(|invoice| Ok::<_, Infallible>(Bytes::from(invoice)));
+        StreamBody::new(stream::iter(first).chain(stream::pending())).into_response()
+    }
+    serve(Router::new().route("/v1/invoices/subscribe", get(subscribe)).with_state(pending))
+}
@@ -0,0 +1,53 @@
+use std::sync::{LazyLock, Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+
+// spaces out requests to an api across every worker in the process, the admin api included
+#[derive(Default)]
+pub struct Throttle {
+    // the earliest the next request may go out
+    next: Mutex<Option<Instant>>
+}
+
+pub static LICHESS_THROTTLE: LazyLock<Throttle> = LazyLock::new(Throttle::default);
+// lichess asks for a minute's pause after a 429
+pub const LICHESS_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+impl Throttle {
+    // books the next free slot and waits for it
+    pub async fn wait(&self, interval: Duration) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = next.map_or(now, |next| next.max(now));
+            *next = Some(slot + interval);
+            slot
+        };
+        sleep_until(slot).await;
+    }
+
+    // holds every request back for a while, on top of the slots already booked
+    pub fn back_off(&self, duration: Duration) {
+        let mut next = self.next.lock().unwrap();
+        let until = Instant::now() + duration;
+        *next = Some(next.map_or(until, |next| next.max(until)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests() {
+        let throttle = Throttle::default();
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.wait(Duration::from_millis(100)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+
+        throttle.back_off(Duration::from_secs(60));
+        throttle.wait(Duration::from_millis(100)).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200) + Duration::from_secs(60));
+    }
+}